@@ -0,0 +1,216 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use std;
+use std::ffi::CStr;
+use std::path::PathBuf;
+
+use nix::libc;
+use nix::unistd;
+
+use client::ExitPolicy;
+use model::ScrollbackPolicy;
+
+const USAGE: &str = "Usage: 6term [OPTIONS] [-e COMMAND [ARGS...]]
+
+Options:
+  -e COMMAND [ARGS...]       run COMMAND instead of the user's shell (must be the last option)
+  --working-directory DIR    start the client in DIR
+  --title TITLE              set the window title (default: 6term)
+  --login                    start the client as a login shell
+  --pty                      run the client on a pseudo-terminal, for programs
+                             that require their stdio to be a TTY
+  --hold                     keep the window open after the client has exited
+                             (same as --on-exit hold)
+  --on-exit ACTION           what to do when the client exits: close (default),
+                             hold, or restart (when the user presses Enter)
+  --class CLASS              set the window class (WM_CLASS)
+  --socket-dir DIR           put the VT6 server socket in DIR (default: $XDG_RUNTIME_DIR/vt6);
+                             DIR must not be accessible to other users
+  --scrollback-lines N       keep at most N lines of history (default: 100000)
+  --scrollback-bytes SIZE    keep at most SIZE bytes of history, e.g. 64M (default: 64M)
+  --scrollback-sections N    keep at most N sections, i.e. commands and their
+                             output (default: 10000)
+                             (each of these can be "unlimited")
+  -h, --help                 show this message";
+
+///Options that can be given on the command line.
+pub struct Options {
+    ///The client program and its arguments. The first element is the path to
+    ///the executable.
+    pub command: Vec<String>,
+    pub working_directory: Option<PathBuf>,
+    pub title: String,
+    pub login: bool,
+    ///Whether the client runs on a pseudo-terminal instead of directly on the
+    ///server socket. The VT6 socket remains available to it in either case.
+    pub pty: bool,
+    pub on_exit: ExitPolicy,
+    pub class: Option<String>,
+    pub socket_dir: Option<PathBuf>,
+    pub scrollback: ScrollbackPolicy,
+}
+
+impl Options {
+    ///Parses the command line of this process. Prints usage information and
+    ///exits if the command line is invalid or help was requested.
+    pub fn from_env() -> Options {
+        match Options::parse(std::env::args().skip(1).collect()) {
+            Ok(Some(opts)) => opts,
+            Ok(None) => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            },
+            Err(msg) => {
+                eprintln!("6term: {}\n{}", msg, USAGE);
+                std::process::exit(2);
+            },
+        }
+    }
+
+    ///Returns None if help was requested.
+    fn parse(args: Vec<String>) -> Result<Option<Options>, String> {
+        let mut opts = Options {
+            command: Vec::new(),
+            working_directory: None,
+            title: "6term".into(),
+            login: false,
+            pty: false,
+            on_exit: ExitPolicy::Close,
+            class: None,
+            socket_dir: None,
+            scrollback: ScrollbackPolicy::default(),
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-e" => {
+                    opts.command = args.by_ref().collect();
+                    if opts.command.is_empty() {
+                        return Err("-e requires a command".into());
+                    }
+                },
+                "--working-directory" => opts.working_directory = Some(required_value(&arg, args.next())?.into()),
+                "--title" => opts.title = required_value(&arg, args.next())?,
+                "--login" => opts.login = true,
+                "--pty" => opts.pty = true,
+                "--hold" => opts.on_exit = ExitPolicy::Hold,
+                "--on-exit" => opts.on_exit = match required_value(&arg, args.next())?.as_str() {
+                    "close" => ExitPolicy::Close,
+                    "hold" => ExitPolicy::Hold,
+                    "restart" => ExitPolicy::OfferRestart,
+                    other => return Err(format!("invalid value for --on-exit: {}", other)),
+                },
+                "--class" => opts.class = Some(required_value(&arg, args.next())?),
+                "--socket-dir" => opts.socket_dir = Some(required_value(&arg, args.next())?.into()),
+                "--scrollback-lines" => opts.scrollback.max_lines = parse_limit(&arg, args.next(), false)?,
+                "--scrollback-bytes" => opts.scrollback.max_bytes = parse_limit(&arg, args.next(), true)?,
+                "--scrollback-sections" => opts.scrollback.max_sections = parse_limit(&arg, args.next(), false)?,
+                _ => return Err(format!("unknown argument: {}", arg)),
+            }
+        }
+
+        if opts.command.is_empty() {
+            //shells need to be told explicitly that they are interactive since
+            //their stdin is not a TTY
+            opts.command = vec![find_user_shell(), "-i".into()];
+        }
+        //we use execve() which does not search $PATH, so do that now
+        if let Some(path) = find_in_path(&opts.command[0]) {
+            opts.command[0] = path;
+        }
+        Ok(Some(opts))
+    }
+
+    ///Returns the argv for the client. For login shells, argv[0] is prefixed
+    ///with a dash (e.g. "-bash") as login(1) does.
+    pub fn client_args(&self) -> Vec<String> {
+        let mut args = self.command.clone();
+        if self.login {
+            let basename = args[0].rsplit('/').next().unwrap_or("").to_string();
+            args[0] = format!("-{}", basename);
+        }
+        args
+    }
+}
+
+fn required_value(arg: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} requires an argument", arg))
+}
+
+///Parses the value of a --scrollback-* option. "unlimited" gives None. If
+///`with_suffix` is true, the number may be followed by K, M or G (powers of
+///1024).
+fn parse_limit(arg: &str, value: Option<String>, with_suffix: bool) -> Result<Option<usize>, String> {
+    let value = required_value(arg, value)?;
+    if value == "unlimited" {
+        return Ok(None);
+    }
+    let (digits, factor) = match value.chars().last() {
+        Some('K') if with_suffix => (&value[..value.len()-1], 1 << 10),
+        Some('M') if with_suffix => (&value[..value.len()-1], 1 << 20),
+        Some('G') if with_suffix => (&value[..value.len()-1], 1 << 30),
+        _ => (value.as_str(), 1),
+    };
+    digits.parse::<usize>().ok()
+        .and_then(|n| n.checked_mul(factor))
+        .map(Some)
+        .ok_or_else(|| format!("invalid value for {}: {}", arg, value))
+}
+
+///Finds the user's preferred shell from $SHELL or the passwd database.
+fn find_user_shell() -> String {
+    if let Some(shell) = std::env::var("SHELL").ok().filter(|s| !s.is_empty()) {
+        return shell;
+    }
+
+    let uid = unistd::getuid();
+    //getpwuid() returns either NULL or a pointer to a static buffer
+    //that stays valid until the next call to getpw*()
+    let shell = unsafe {
+        let pw = libc::getpwuid(uid.into());
+        if pw.is_null() || (*pw).pw_shell.is_null() {
+            None
+        } else {
+            CStr::from_ptr((*pw).pw_shell).to_str().ok().map(|s| s.to_string())
+        }
+    };
+    match shell {
+        Some(ref s) if !s.is_empty() => s.clone(),
+        _ => "/bin/sh".into(),
+    }
+}
+
+///If `command` is a bare name (without slashes), looks for an executable of
+///that name in $PATH.
+fn find_in_path(command: &str) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    if command.contains('/') {
+        return None;
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(command))
+        .find(|candidate| match candidate.metadata() {
+            Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        })
+        .and_then(|candidate| candidate.to_str().map(|s| s.to_string()))
+}
@@ -0,0 +1,555 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use std;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::future::{self, Loop};
+use futures::stream;
+use futures::sync::mpsc;
+use nix;
+use nix::libc::SIGCHLD;
+use nix::sys::signal::{kill, Signal as UnixSignal};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use tokio::prelude::*;
+use tokio_signal::unix::Signal;
+
+use cli;
+use model;
+use pty::{self, Pty};
+use window;
+
+///What happens when the client program exits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExitPolicy {
+    ///Close the window.
+    Close,
+    ///Keep the window open, so that the user can read the final output.
+    Hold,
+    ///Keep the window open, and restart the client when the user presses Enter.
+    OfferRestart,
+}
+
+///Refers to the running client (if any), so that it can be controlled from
+///other threads. Also keeps the pseudo-terminal relays of all clients, so that
+///their threads can be joined on shutdown.
+#[derive(Clone, Default)]
+pub struct ClientHandle(Arc<Mutex<Option<RunningClient>>>, Arc<Mutex<Vec<pty::Relay>>>);
+
+///A client process that has been started. Each client is the leader of its own
+///process group.
+pub struct RunningClient {
+    pid: Pid,
+    stdin: Stdin,
+    ///Only for clients on a pseudo-terminal. Moved into the ClientHandle by
+    ///ClientHandle::set().
+    relay: Option<pty::Relay>,
+}
+
+///A handle on the client's stdin, used to signal end of input.
+enum Stdin {
+    ///The client-side end of the stdio connection to the server.
+    Socket(UnixStream),
+    ///A duplicate of the master side of the client's pseudo-terminal.
+    Pty(File),
+}
+
+impl ClientHandle {
+    fn set(&self, mut client: Option<RunningClient>) {
+        if let Some(relay) = client.as_mut().and_then(|c| c.relay.take()) {
+            self.1.lock().unwrap().push(relay);
+        }
+        *self.0.lock().unwrap() = client;
+    }
+
+    ///Sends SIGHUP to the client's process group, as a terminal does when it
+    ///goes away. Does nothing if no client is running.
+    pub fn hangup(&self) {
+        if let Some(ref client) = *self.0.lock().unwrap() {
+            client.kill(UnixSignal::SIGHUP);
+        }
+    }
+
+    ///Sends SIGKILL to the client's process group, and reaps the client. Used
+    ///on shutdown when the client did not exit on SIGHUP.
+    pub fn kill(&self) {
+        if let Some(ref client) = *self.0.lock().unwrap() {
+            client.kill(UnixSignal::SIGKILL);
+            //the watch() future may have reaped the client already
+            match waitpid(client.pid, None) {
+                Ok(_) | Err(nix::Error::Sys(Errno::ECHILD)) => {},
+                Err(e) => error!("waitpid failed: {}", e),
+            }
+        }
+    }
+
+    ///Stops the pseudo-terminal relays of all clients, and joins their threads
+    ///unless they do not finish until `deadline`. Returns whether all threads
+    ///were joined.
+    pub fn stop_relays(&self, deadline: Instant) -> bool {
+        let relays = std::mem::replace(&mut *self.1.lock().unwrap(), Vec::new());
+        relays.into_iter().fold(true, |all_joined, relay| relay.stop(deadline) && all_joined)
+    }
+
+    ///Sends a signal to the client's foreground process group. For clients on
+    ///a pseudo-terminal, this goes through the terminal's line discipline, which
+    ///knows the foreground process group. Otherwise, the signal goes to the
+    ///client's process group. Returns false if the signal was refused.
+    pub fn signal(&self, signal: model::ControlSignal) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        let client = match *guard {
+            Some(ref mut client) => client,
+            None => return false,
+        };
+        match client.stdin {
+            Stdin::Pty(ref mut master) => {
+                let ch = match signal {
+                    model::ControlSignal::Interrupt => b"\x03",
+                    model::ControlSignal::Quit => b"\x1C",
+                    model::ControlSignal::Suspend => b"\x1A",
+                };
+                if let Err(e) = master.write_all(ch) {
+                    error!("cannot write to pseudo-terminal: {}", e);
+                }
+                return true;
+            },
+            Stdin::Socket(_) => {},
+        }
+        let signal = match signal {
+            model::ControlSignal::Interrupt => UnixSignal::SIGINT,
+            model::ControlSignal::Quit => UnixSignal::SIGQUIT,
+            //without a terminal, there is no job control that could resume a
+            //stopped job, so the client would be stuck for good
+            model::ControlSignal::Suspend => return false,
+        };
+        client.kill(signal);
+        true
+    }
+
+    ///Tells the client about a new screen size (columns, rows). This only has
+    ///an effect for clients on a pseudo-terminal.
+    pub fn resize(&self, size: (usize, usize)) {
+        if let Some(RunningClient { stdin: Stdin::Pty(ref master), .. }) = *self.0.lock().unwrap() {
+            if let Err(e) = pty::set_size(master.as_raw_fd(), size) {
+                error!("cannot resize pseudo-terminal: {}", e);
+            }
+        }
+    }
+
+    ///Signals end of input to the client: Its stdin is half-closed, or on a
+    ///pseudo-terminal, EOF is sent through the line discipline. Returns false
+    ///if this was refused.
+    pub fn end_input(&self) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        let result = match *guard {
+            Some(RunningClient { stdin: Stdin::Pty(ref mut master), .. }) => master.write_all(b"\x04"),
+            //shutting down the reading side of the client's end makes its
+            //reads return EOF, while its output still reaches the server;
+            //but this cannot be undone, and the client's stdio is shared with
+            //all its child processes (e.g. the jobs of a shell), so this is
+            //only safe while the client is the only one that could be reading
+            Some(RunningClient { pid, stdin: Stdin::Socket(ref stream), .. }) => {
+                if has_children(pid) {
+                    return false;
+                }
+                stream.shutdown(Shutdown::Read)
+            },
+            None => return false,
+        };
+        if let Err(e) = result {
+            error!("cannot close client input: {}", e);
+        }
+        true
+    }
+}
+
+impl RunningClient {
+    fn kill(&self, signal: UnixSignal) {
+        //a negative PID refers to the process group
+        if let Err(e) = kill(Pid::from_raw(-self.pid.as_raw()), signal) {
+            error!("cannot send {:?} to client process group {}: {}", signal, self.pid, e);
+        }
+    }
+}
+
+type BoxFuture<T> = Box<Future<Item = T, Error = ()> + Send>;
+
+///Returns a future that runs the client program and watches over it until it
+///exits for good (according to the ExitPolicy). Each item received on
+///`restart_rx` restarts an exited client if the policy allows that. See
+///server::make_server_future() for the meaning of the socket paths.
+pub fn supervise(
+    socket_path: PathBuf,
+    stdio_socket_path: PathBuf,
+    opts: cli::Options,
+    model: Arc<Mutex<model::Document>>,
+    window_handle: window::WindowHandle,
+    restart_rx: mpsc::Receiver<()>,
+    handle: ClientHandle,
+) -> impl Future<Item = (), Error = ()> {
+    //loop_fn() runs the first iteration immediately, but the client must only
+    //be started once the eventloop is running
+    future::lazy(move || future::loop_fn(restart_rx, move |restart_rx| -> BoxFuture<Loop<(), mpsc::Receiver<()>>> {
+        let mut policy = opts.on_exit;
+        let exit_message: BoxFuture<String> = match spawn(&socket_path, &stdio_socket_path, &opts, &model) {
+            Ok(client) => {
+                let pid = client.pid;
+                handle.set(Some(client));
+                //the view may have measured the screen since the client was
+                //spawned (the other case is handled by Window::main)
+                let size = model.lock().unwrap().screen_size();
+                handle.resize(size);
+                Box::new(watch(pid).map(describe_exit))
+            },
+            Err(e) => {
+                //keep the window open, so that the user can see what went wrong
+                if policy == ExitPolicy::Close {
+                    policy = ExitPolicy::Hold;
+                }
+                let name = opts.command[0].rsplit('/').next().unwrap_or("");
+                Box::new(future::ok(format!("{}: {}", name, e)))
+            },
+        };
+
+        let model = model.clone();
+        let window_handle = window_handle.clone();
+        let handle = handle.clone();
+        Box::new(exit_message.and_then(move |message| -> BoxFuture<Loop<(), mpsc::Receiver<()>>> {
+            handle.set(None);
+            info!("{}", message);
+            model.lock().unwrap().report_client_exit(&message, policy == ExitPolicy::OfferRestart);
+            window_handle.redraw();
+
+            match policy {
+                ExitPolicy::Close => {
+                    window_handle.close();
+                    Box::new(future::ok(Loop::Break(())))
+                },
+                ExitPolicy::Hold => Box::new(future::ok(Loop::Break(()))),
+                ExitPolicy::OfferRestart => Box::new(restart_rx.into_future()
+                    .map_err(|_| ())
+                    .map(move |(request, restart_rx)| match request {
+                        Some(()) => {
+                            model.lock().unwrap().report_client_restart();
+                            Loop::Continue(restart_rx)
+                        },
+                        //GUI has gone away
+                        None => Loop::Break(()),
+                    })
+                ),
+            }
+        }))
+    }))
+}
+
+///Returns a future that resolves when the given child process has exited. The
+///child is reaped when SIGCHLD arrives, so no thread needs to block in waitpid().
+fn watch(pid: Pid) -> impl Future<Item = Option<WaitStatus>, Error = ()> {
+    //The first poll registers the signal handler. After that, we check once
+    //in case the child exited before the handler was registered.
+    Signal::new(SIGCHLD)
+        .map(|signals| stream::once(Ok(SIGCHLD)).chain(signals))
+        .flatten_stream()
+        .map_err(|e| error!("cannot watch for SIGCHLD: {}", e))
+        .filter_map(move |_| match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(status @ WaitStatus::Exited(..)) | Ok(status @ WaitStatus::Signaled(..)) => Some(Some(status)),
+            //still running, or only stopped/continued
+            Ok(_) => None,
+            Err(e) => {
+                error!("waitpid failed: {}", e);
+                Some(None)
+            },
+        })
+        .into_future()
+        .map(|(status, _)| status.and_then(|s| s))
+        .map_err(|(e, _)| e)
+}
+
+fn describe_exit(status: Option<WaitStatus>) -> String {
+    match status {
+        Some(WaitStatus::Exited(_, code)) => format!("client exited with status {}", code),
+        Some(WaitStatus::Signaled(_, signal, _)) => format!("client killed by signal {:?}", signal),
+        _ => "client could not be watched".into(),
+    }
+}
+
+///Why the client could not be started.
+#[derive(Debug)]
+pub enum SpawnError {
+    ///An argument or environment variable contains a NUL byte.
+    InvalidString(String),
+    ///The client's stdio could not be connected to the server socket.
+    Connect(std::io::Error),
+    ///A system call in the parent process failed.
+    Parent(&'static str, nix::Error),
+    ///The pseudo-terminal of the client could not be connected to the server.
+    Relay(std::io::Error),
+    ///A system call in the child process failed before the client program
+    ///could be executed.
+    Child(ChildStep, Errno),
+}
+
+///The steps that the child process performs before executing the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChildStep {
+    SetProcessGroup,
+    AttachTerminal,
+    RedirectStdio,
+    ChangeDirectory,
+    Execute,
+}
+
+impl ChildStep {
+    fn from_u8(value: u8) -> Option<ChildStep> {
+        use self::ChildStep::*;
+        [SetProcessGroup, AttachTerminal, RedirectStdio, ChangeDirectory, Execute].iter()
+            .find(|step| **step as u8 == value).cloned()
+    }
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use self::SpawnError::*;
+        match *self {
+            InvalidString(ref s) => write!(f, "{:?} contains a NUL byte", s),
+            Connect(ref e) => write!(f, "cannot connect to VT6 server socket: {}", e),
+            Parent(call, ref e) => write!(f, "{}: {}", call, e),
+            Relay(ref e) => write!(f, "cannot relay pseudo-terminal: {}", e),
+            Child(ChildStep::SetProcessGroup, errno) => write!(f, "setpgid: {}", errno.desc()),
+            Child(ChildStep::AttachTerminal, errno) => write!(f, "cannot attach to pseudo-terminal: {}", errno.desc()),
+            Child(ChildStep::RedirectStdio, errno) => write!(f, "dup2: {}", errno.desc()),
+            Child(ChildStep::ChangeDirectory, errno) => write!(f, "chdir: {}", errno.desc()),
+            Child(ChildStep::Execute, errno) => write!(f, "{}", errno.desc()),
+        }
+    }
+}
+
+///Whether the given process has child processes, according to /proc. When in
+///doubt (e.g. when /proc is not available), the answer is yes.
+fn has_children(pid: Pid) -> bool {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("cannot list /proc: {}", e);
+            return true;
+        },
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        //only the numeric entries are processes
+        let is_process = entry.file_name().to_str().map_or(false, |name| name.bytes().all(|b| b.is_ascii_digit()));
+        if !is_process {
+            continue;
+        }
+        //the process may have exited in the meantime
+        if let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) {
+            if parent_pid(&stat) == Some(pid.as_raw()) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+///Extracts the parent PID from the contents of /proc/PID/stat. The process
+///name (in parentheses) may contain spaces and parentheses itself, so the
+///fields are counted from the last closing parenthesis.
+fn parent_pid(stat: &str) -> Option<i32> {
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn to_cstring<T: Into<Vec<u8>>>(value: T) -> Result<CString, SpawnError> {
+    CString::new(value).map_err(|e| {
+        let bytes = e.into_vec();
+        SpawnError::InvalidString(String::from_utf8_lossy(&bytes).into_owned())
+    })
+}
+
+///Starts the client program with its stdio connected to the VT6 server socket,
+///either directly or through a pseudo-terminal (if requested). Failures in the
+///child process before exec() are reported back through a pipe that is closed
+///automatically by a successful exec().
+fn spawn(socket_path: &Path, stdio_socket_path: &Path, opts: &cli::Options, model: &Arc<Mutex<model::Document>>) -> Result<RunningClient, SpawnError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    //before forking, make all necessary allocations
+    let mut env = Vec::new();
+    for (k, v) in std::env::vars_os() {
+        match k.as_bytes() {
+            b"SHELL" | b"LINES" | b"COLUMNS" | b"VT6" | b"TERM" | b"COLORTERM" => continue,
+            _ => {},
+        }
+        let mut var = k.as_bytes().to_vec();
+        var.push(b'=');
+        var.extend_from_slice(v.as_bytes());
+        env.push(to_cstring(var)?);
+    }
+    //tell the client where to find the VT6 server, and what kind of terminal this is
+    let mut vt6_var = b"VT6=".to_vec();
+    vt6_var.extend_from_slice(socket_path.as_os_str().as_bytes());
+    env.push(to_cstring(vt6_var)?);
+    env.push(to_cstring("TERM=xterm-256color")?);
+    env.push(to_cstring("COLORTERM=truecolor")?);
+    let args = opts.client_args().into_iter().map(to_cstring).collect::<Result<Vec<_>, _>>()?;
+    let command = to_cstring(opts.command[0].clone())?;
+
+    //open stdio for the child process (on the stdio socket, which tells the
+    //server that this is a stdio connection)
+    use std::os::unix::io::IntoRawFd;
+    let stream = UnixStream::connect(stdio_socket_path).map_err(SpawnError::Connect)?;
+
+    let pty = if opts.pty {
+        let size = model.lock().unwrap().screen_size();
+        Some(Pty::open(size).map_err(|e| SpawnError::Parent("openpty", e))?)
+    } else {
+        None
+    };
+
+    use nix::unistd::*;
+    let (error_rx, error_tx) = match pipe2(OFlag::O_CLOEXEC) {
+        Ok(fds) => fds,
+        Err(e) => {
+            if let Some(pty) = pty {
+                pty.close();
+            }
+            return Err(SpawnError::Parent("pipe2", e));
+        },
+    };
+
+    match fork() {
+        Err(e) => {
+            let _ = close(error_rx);
+            let _ = close(error_tx);
+            if let Some(pty) = pty {
+                pty.close();
+            }
+            Err(SpawnError::Parent("fork", e))
+        },
+        Ok(ForkResult::Parent { child, .. }) => {
+            let _ = close(error_tx);
+            let (stdin, relay) = match pty {
+                Some(pty) => {
+                    //the child becomes a process group leader by itself via
+                    //setsid(), which fails if we make it one first
+                    let result = fcntl(pty.master, FcntlArg::F_DUPFD_CLOEXEC(0))
+                        .map_err(|e| SpawnError::Parent("fcntl", e))
+                        .and_then(|master| {
+                            let relay = pty.relay(stream).map_err(SpawnError::Relay)?;
+                            Ok((Stdin::Pty(unsafe { File::from_raw_fd(master) }), Some(relay)))
+                        });
+                    match result {
+                        Ok(result) => result,
+                        Err(e) => {
+                            let _ = close(error_rx);
+                            let _ = kill(child, UnixSignal::SIGKILL);
+                            let _ = waitpid(child, None);
+                            return Err(e);
+                        },
+                    }
+                },
+                None => {
+                    //also done in the child; whichever comes first avoids the
+                    //race where we signal the process group before it exists
+                    let _ = setpgid(child, child);
+                    (Stdin::Socket(stream), None)
+                },
+            };
+
+            //EOF means that exec() succeeded and closed the pipe
+            let mut report = [0u8; 5];
+            let mut received = 0;
+            while received < report.len() {
+                match read(error_rx, &mut report[received..]) {
+                    Ok(0) => break,
+                    Ok(n) => received += n,
+                    Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                    Err(e) => {
+                        let _ = close(error_rx);
+                        return Err(SpawnError::Parent("read", e));
+                    },
+                }
+            }
+            let _ = close(error_rx);
+            if received < report.len() {
+                return Ok(RunningClient { pid: child, stdin, relay });
+            }
+
+            //the child has failed and exits immediately
+            let _ = waitpid(child, None);
+            let errno = report[1..5].iter().rev().fold(0i32, |acc, b| (acc << 8) | (*b as i32));
+            let errno = Errno::from_i32(errno);
+            match ChildStep::from_u8(report[0]) {
+                Some(step) => Err(SpawnError::Child(step, errno)),
+                None => Err(SpawnError::Parent("fork", nix::Error::Sys(errno))),
+            }
+        },
+        Ok(ForkResult::Child) => {
+            let _ = close(error_rx);
+            //put the client in its own process group, so that the whole job
+            //can be signaled at once
+            let stdio_fd = match pty {
+                Some(ref pty) => {
+                    let _ = close(pty.master);
+                    pty.attach().unwrap_or_else(|e| report_child_failure(error_tx, ChildStep::AttachTerminal, e));
+                    pty.slave
+                },
+                None => {
+                    setpgid(Pid::from_raw(0), Pid::from_raw(0))
+                        .unwrap_or_else(|e| report_child_failure(error_tx, ChildStep::SetProcessGroup, e));
+                    stream.into_raw_fd()
+                },
+            };
+            for fd in 0..3 {
+                dup2(stdio_fd, fd).unwrap_or_else(|e| report_child_failure(error_tx, ChildStep::RedirectStdio, e));
+            }
+            if stdio_fd > 2 {
+                let _ = close(stdio_fd);
+            }
+            if let Some(ref dir) = opts.working_directory {
+                chdir(dir.as_path()).unwrap_or_else(|e| report_child_failure(error_tx, ChildStep::ChangeDirectory, e));
+            }
+            match execve(&command, &args, &env) {
+                Ok(void) => match void {},
+                Err(e) => report_child_failure(error_tx, ChildStep::Execute, e),
+            }
+        },
+    }
+}
+
+///Used by the child process after fork() to report a failure to the parent
+///process through the error pipe. Only async-signal-safe functions are used.
+fn report_child_failure(error_tx: RawFd, step: ChildStep, err: nix::Error) -> ! {
+    let errno = match err {
+        nix::Error::Sys(errno) => errno as i32,
+        _ => 0,
+    };
+    let report = [step as u8, errno as u8, (errno >> 8) as u8, (errno >> 16) as u8, (errno >> 24) as u8];
+    let _ = nix::unistd::write(error_tx, &report);
+    unsafe { nix::libc::_exit(127) }
+}
@@ -0,0 +1,65 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+pub mod sig;
+pub mod term;
+
+use vt6;
+use vt6::common::core::msg;
+
+///The full handler chain of 6term. Each handler takes care of one module and
+///passes everything else on to the next one.
+pub type Handler = vt6::server::core::Handler<term::Handler<sig::Handler<vt6::server::RejectHandler>>>;
+
+pub fn new() -> Handler {
+    let handler = vt6::server::RejectHandler {};
+    let handler = sig::Handler::new(handler);
+    let handler = term::Handler::new(handler);
+    vt6::server::core::Handler::new(handler)
+}
+
+///Connection state that is required by the handlers in this module, in addition
+///to what vt6::server::Connection provides.
+pub trait Connection: vt6::server::Connection {
+    fn output_protected(&self) -> bool;
+    fn set_output_protected(&mut self, value: bool);
+    fn input_immediate(&self) -> bool;
+    fn set_input_immediate(&mut self, value: bool);
+    fn input_echo(&self) -> bool;
+    fn set_input_echo(&mut self, value: bool);
+}
+
+pub fn decode_bool(value: &[u8]) -> Option<bool> {
+    match value {
+        b"t" => Some(true),
+        b"f" => Some(false),
+        _ => None,
+    }
+}
+
+pub fn encode_bool(value: bool) -> &'static str {
+    if value { "t" } else { "f" }
+}
+
+///Renders a `(have NAME VALUE)` message into the send buffer.
+pub fn publish_property(send_buffer: &mut [u8], name: &str, value: &str) -> Option<usize> {
+    let mut f = msg::MessageFormatter::new(send_buffer, "have", 2);
+    f.add_argument(name);
+    f.add_argument(value);
+    f.finalize().ok()
+}
@@ -0,0 +1,69 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use vt6;
+use vt6::common::core::msg;
+
+use handler::Connection;
+use model;
+
+///Handler for the sig module. Everything that is not part of the sig module is
+///passed on to the next handler.
+///
+///The sig module only consists of events that the server sends when the user
+///presses a control key (see format_event()), so that VT6-aware clients can
+///handle these themselves instead of receiving a Unix signal.
+pub struct Handler<H> {
+    next: H,
+}
+
+impl<H> Handler<H> {
+    pub fn new(next: H) -> Handler<H> {
+        Handler { next }
+    }
+}
+
+impl<C: Connection, H: vt6::server::Handler<C>> vt6::server::Handler<C> for Handler<H> {
+    fn handle(&self, msg: &msg::Message, conn: &mut C, send_buffer: &mut [u8]) -> Option<usize> {
+        //the sig module does not define any messages that clients can send
+        self.next.handle(msg, conn, send_buffer)
+    }
+
+    fn can_use_module(&self, name: &str, major_version: u16, conn: &C) -> Option<u16> {
+        if name == "sig" {
+            return if major_version == 1 { Some(0) } else { None };
+        }
+        self.next.can_use_module(name, major_version, conn)
+    }
+
+    fn handle_property<'c>(&self, name: &str, requested_value: Option<&[u8]>, conn: &mut C, send_buffer: &mut [u8]) -> Option<usize> {
+        //the sig module does not define any properties
+        self.next.handle_property(name, requested_value, conn, send_buffer)
+    }
+}
+
+///Renders the event for the given control key, e.g. `(sig.interrupt)` for
+///Ctrl+C, into the send buffer.
+pub fn format_event(signal: model::ControlSignal, send_buffer: &mut [u8]) -> Option<usize> {
+    let name = match signal {
+        model::ControlSignal::Interrupt => "sig.interrupt",
+        model::ControlSignal::Quit => "sig.quit",
+        model::ControlSignal::Suspend => "sig.suspend",
+    };
+    msg::MessageFormatter::new(send_buffer, name, 0).finalize().ok()
+}
@@ -0,0 +1,77 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use vt6;
+use vt6::common::core::msg;
+
+use handler::{decode_bool, encode_bool, publish_property, Connection};
+
+///Handler for the term module. Everything that is not part of the term module is
+///passed on to the next handler.
+pub struct Handler<H> {
+    next: H,
+}
+
+impl<H> Handler<H> {
+    pub fn new(next: H) -> Handler<H> {
+        Handler { next }
+    }
+}
+
+impl<C: Connection, H: vt6::server::Handler<C>> vt6::server::Handler<C> for Handler<H> {
+    fn handle(&self, msg: &msg::Message, conn: &mut C, send_buffer: &mut [u8]) -> Option<usize> {
+        //the term module does not define any messages besides properties
+        self.next.handle(msg, conn, send_buffer)
+    }
+
+    fn can_use_module(&self, name: &str, major_version: u16, conn: &C) -> Option<u16> {
+        if name == "term" {
+            return if major_version == 1 { Some(0) } else { None };
+        }
+        self.next.can_use_module(name, major_version, conn)
+    }
+
+    fn handle_property<'c>(&self, name: &str, requested_value: Option<&[u8]>, conn: &mut C, send_buffer: &mut [u8]) -> Option<usize> {
+        if !name.starts_with("term.") || conn.is_module_enabled("term").is_none() {
+            return self.next.handle_property(name, requested_value, conn, send_buffer);
+        }
+
+        match name {
+            "term.output-protected" => {
+                //invalid values are ignored, and the current value is reported instead
+                if let Some(value) = requested_value.and_then(decode_bool) {
+                    conn.set_output_protected(value);
+                }
+                publish_property(send_buffer, name, encode_bool(conn.output_protected()))
+            },
+            "term.input-immediate" => {
+                if let Some(value) = requested_value.and_then(decode_bool) {
+                    conn.set_input_immediate(value);
+                }
+                publish_property(send_buffer, name, encode_bool(conn.input_immediate()))
+            },
+            "term.input-echo" => {
+                if let Some(value) = requested_value.and_then(decode_bool) {
+                    conn.set_input_echo(value);
+                }
+                publish_property(send_buffer, name, encode_bool(conn.input_echo()))
+            },
+            _ => self.next.handle_property(name, requested_value, conn, send_buffer),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use gdk;
+use gdk::enums::key;
+
+///Encodes a keystroke as the byte sequence that a client with immediate input
+///receives for it. This follows the conventions of xterm, since that is what
+///most programs expect. Returns None for keys that do not produce input on
+///their own (e.g. modifier keys).
+pub fn encode(keyval: key::Key, state: gdk::ModifierType) -> Option<String> {
+    let shift = state.contains(gdk::ModifierType::SHIFT_MASK);
+    let alt = state.contains(gdk::ModifierType::MOD1_MASK);
+    let ctrl = state.contains(gdk::ModifierType::CONTROL_MASK);
+    //xterm's modifier parameter for CSI sequences (1 means no modifiers)
+    let modifiers = 1 + (shift as u8) + 2 * (alt as u8) + 4 * (ctrl as u8);
+
+    let encoded = match keyval {
+        key::Up    | key::KP_Up    => cursor_key('A', modifiers),
+        key::Down  | key::KP_Down  => cursor_key('B', modifiers),
+        key::Right | key::KP_Right => cursor_key('C', modifiers),
+        key::Left  | key::KP_Left  => cursor_key('D', modifiers),
+        key::Home  | key::KP_Home  => cursor_key('H', modifiers),
+        key::End   | key::KP_End   => cursor_key('F', modifiers),
+        key::Insert    | key::KP_Insert    => tilde_key(2, modifiers),
+        key::Delete    | key::KP_Delete    => tilde_key(3, modifiers),
+        key::Page_Up   | key::KP_Page_Up   => tilde_key(5, modifiers),
+        key::Page_Down | key::KP_Page_Down => tilde_key(6, modifiers),
+        key::F1 => function_key('P', modifiers),
+        key::F2 => function_key('Q', modifiers),
+        key::F3 => function_key('R', modifiers),
+        key::F4 => function_key('S', modifiers),
+        key::F5  => tilde_key(15, modifiers),
+        key::F6  => tilde_key(17, modifiers),
+        key::F7  => tilde_key(18, modifiers),
+        key::F8  => tilde_key(19, modifiers),
+        key::F9  => tilde_key(20, modifiers),
+        key::F10 => tilde_key(21, modifiers),
+        key::F11 => tilde_key(23, modifiers),
+        key::F12 => tilde_key(24, modifiers),
+        key::ISO_Left_Tab => "\x1B[Z".into(),
+        key::Tab if shift => "\x1B[Z".into(),
+        key::Return | key::KP_Enter => with_alt("\r".into(), alt),
+        key::BackSpace => with_alt("\x7F".into(), alt),
+        key::Escape => with_alt("\x1B".into(), alt),
+        _ => {
+            let ch = gdk::keyval_to_unicode(keyval)?;
+            let text = if ctrl { control_char(ch).to_string() } else { ch.to_string() };
+            with_alt(text, alt)
+        },
+    };
+    Some(encoded)
+}
+
+///Arrow keys, Home and End: `CSI A` or, with modifiers, `CSI 1 ; m A`.
+fn cursor_key(final_char: char, modifiers: u8) -> String {
+    if modifiers == 1 {
+        format!("\x1B[{}", final_char)
+    } else {
+        format!("\x1B[1;{}{}", modifiers, final_char)
+    }
+}
+
+///F1 to F4: `SS3 P` or, with modifiers, `CSI 1 ; m P`.
+fn function_key(final_char: char, modifiers: u8) -> String {
+    if modifiers == 1 {
+        format!("\x1BO{}", final_char)
+    } else {
+        format!("\x1B[1;{}{}", modifiers, final_char)
+    }
+}
+
+///Editing keys and F5 to F12: `CSI n ~` or, with modifiers, `CSI n ; m ~`.
+fn tilde_key(code: u8, modifiers: u8) -> String {
+    if modifiers == 1 {
+        format!("\x1B[{}~", code)
+    } else {
+        format!("\x1B[{};{}~", code, modifiers)
+    }
+}
+
+///Alt prefixes the key's input with ESC.
+fn with_alt(text: String, alt: bool) -> String {
+    if alt { format!("\x1B{}", text) } else { text }
+}
+
+///Maps Ctrl+key to the corresponding C0 control character, e.g. Ctrl+C to ETX.
+fn control_char(ch: char) -> char {
+    match ch {
+        'a'...'z' | 'A'...'Z' => ((ch as u8) & 0x1F) as char,
+        '@' | ' ' | '2' => '\x00',
+        '[' | '3' => '\x1B',
+        '\\' | '4' => '\x1C',
+        ']' | '5' => '\x1D',
+        '^' | '6' => '\x1E',
+        '_' | '7' | '/' => '\x1F',
+        '?' | '8' => '\x7F',
+        //no control character for this key, send it as is
+        _ => ch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gdk::ModifierType;
+    use gdk::enums::key;
+
+    use super::*;
+
+    const SHIFT: ModifierType = ModifierType::SHIFT_MASK;
+    const ALT: ModifierType = ModifierType::MOD1_MASK;
+    const CTRL: ModifierType = ModifierType::CONTROL_MASK;
+
+    fn check(keyval: key::Key, state: ModifierType, expected: &str) {
+        assert_eq!(encode(keyval, state), Some(expected.to_string()), "keyval {} with {:?}", keyval, state);
+    }
+
+    #[test]
+    fn modifier_parameter() {
+        check(key::Up, ModifierType::empty(), "\x1B[A");
+        check(key::Up, SHIFT, "\x1B[1;2A");
+        check(key::Up, ALT, "\x1B[1;3A");
+        check(key::Up, SHIFT | ALT, "\x1B[1;4A");
+        check(key::Up, CTRL, "\x1B[1;5A");
+        check(key::Up, CTRL | SHIFT | ALT, "\x1B[1;8A");
+        check(key::KP_Left, CTRL, "\x1B[1;5D");
+        check(key::End, ModifierType::empty(), "\x1B[F");
+    }
+
+    #[test]
+    fn function_keys() {
+        //F1 to F4 use SS3 without modifiers, and CSI with modifiers
+        check(key::F1, ModifierType::empty(), "\x1BOP");
+        check(key::F2, ModifierType::empty(), "\x1BOQ");
+        check(key::F3, ModifierType::empty(), "\x1BOR");
+        check(key::F4, ModifierType::empty(), "\x1BOS");
+        check(key::F1, SHIFT, "\x1B[1;2P");
+        check(key::F4, CTRL, "\x1B[1;5S");
+    }
+
+    #[test]
+    fn tilde_keys() {
+        check(key::Insert, ModifierType::empty(), "\x1B[2~");
+        check(key::KP_Delete, ModifierType::empty(), "\x1B[3~");
+        check(key::Page_Up, CTRL, "\x1B[5;5~");
+        check(key::Page_Down, SHIFT, "\x1B[6;2~");
+        check(key::F5, ModifierType::empty(), "\x1B[15~");
+        check(key::F11, ModifierType::empty(), "\x1B[23~");
+        check(key::F12, ALT, "\x1B[24;3~");
+    }
+
+    #[test]
+    fn other_keys() {
+        check(key::Tab, SHIFT, "\x1B[Z");
+        check(key::ISO_Left_Tab, ModifierType::empty(), "\x1B[Z");
+        check(key::Return, ModifierType::empty(), "\r");
+        check(key::BackSpace, ALT, "\x1B\x7F");
+        check(key::a, ModifierType::empty(), "a");
+        check(key::a, ALT, "\x1Ba");
+        check(key::c, CTRL, "\x03");
+        check(key::C, CTRL | SHIFT, "\x03");
+        check(key::c, CTRL | ALT, "\x1B\x03");
+        assert_eq!(encode(key::Shift_L, SHIFT), None);
+    }
+
+    #[test]
+    fn control_chars() {
+        assert_eq!(control_char('a'), '\x01');
+        assert_eq!(control_char('Z'), '\x1A');
+        assert_eq!(control_char('@'), '\x00');
+        assert_eq!(control_char(' '), '\x00');
+        assert_eq!(control_char('2'), '\x00');
+        assert_eq!(control_char('['), '\x1B');
+        assert_eq!(control_char('\\'), '\x1C');
+        assert_eq!(control_char(']'), '\x1D');
+        assert_eq!(control_char('6'), '\x1E');
+        assert_eq!(control_char('/'), '\x1F');
+        assert_eq!(control_char('?'), '\x7F');
+        //keys without a control character are sent as is
+        assert_eq!(control_char('1'), '1');
+        assert_eq!(control_char('ä'), 'ä');
+    }
+}
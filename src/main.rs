@@ -28,70 +28,143 @@ extern crate pango;
 extern crate pangocairo;
 extern crate simple_logger;
 extern crate tokio;
+extern crate tokio_signal;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 extern crate vt6;
 extern crate vt6tokio;
 extern crate vte;
 
+mod cli;
+mod client;
+mod handler;
+mod keyboard;
 mod model;
+mod pty;
 mod server;
 mod view;
 mod window;
 mod util;
 
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 
 fn main() {
+    let opts = cli::Options::from_env();
     simple_logger::init().unwrap();
 
-    let runtime_dir = find_runtime_dir().unwrap_or_else(|_| std::process::exit(1));
+    let runtime_dir = find_runtime_dir(opts.socket_dir.clone()).unwrap_or_else(|_| std::process::exit(1));
     let socket_path = runtime_dir.join(std::process::id().to_string());
+    let stdio_socket_path = runtime_dir.join(format!("{}.stdio", std::process::id()));
+    for path in &[&socket_path, &stdio_socket_path] {
+        remove_stale_socket(path).unwrap_or_else(|_| std::process::exit(1));
+    }
 
     //setup the model shared by all threads
     let model = model::Document::new();
     {
         let mut document = model.lock().unwrap();
+        document.scrollback = opts.scrollback;
         let s = document.make_section();
         document.sections.push(s);
     } //drop MutexGuard<Document>
 
     //setup channel for communication from GUI thread to Tokio eventloop
     let (event_tx, event_rx) = mpsc::channel(10);
-    let mut win = window::Window::new();
+    let mut win = window::Window::new(&opts.title, opts.class.as_ref().map(|s| s.as_str()));
 
-    let server = match server::make_server_future(socket_path.clone(), event_rx, model.clone(), win.handle()) {
+    let server = match server::make_server_future(socket_path.clone(), stdio_socket_path.clone(), event_rx, model.clone(), win.handle()) {
         Ok(s) => s,
         Err(e) => {
             error!("failed to initialize VT6 server socket: {}", e);
             std::process::exit(1);
         },
     };
+    //nobody else can connect in the meantime, since the runtime directory is
+    //only accessible to us
+    for path in &[&socket_path, &stdio_socket_path] {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+            error!("chmod {}: {}", path.to_string_lossy(), e);
+            std::process::exit(1);
+        }
+    }
 
-    let join_handle1 = std::thread::spawn(move || {
+    //the client is started and watched from within the Tokio eventloop
+    let (restart_tx, restart_rx) = mpsc::channel(1);
+    let client_handle = client::ClientHandle::default();
+    let client = client::supervise(socket_path.clone(), stdio_socket_path.clone(), opts, model.clone(), win.handle(), restart_rx, client_handle.clone());
+
+    //the eventloop runs until the GUI thread asks it to shut down and all
+    //connections have been closed
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let drain_model = model.clone();
+    let eventloop = std::thread::spawn(move || {
         use futures::Future;
         use tokio::runtime::Runtime;
 
         let mut rt = Runtime::new().unwrap();
-        rt.block_on(server).unwrap();
+        let running = server.join(client).map(|_| ());
+        //the server future keeps running while the connections drain, so that
+        //the clients' remaining output is still read
+        let drained = shutdown_rx.map_err(|_| ()).and_then(move |_| server::wait_for_drain(drain_model));
+        let _ = rt.block_on(running.select2(drained));
         rt.shutdown_now().wait().unwrap();
+        let _ = done_tx.send(());
     });
 
-    let join_handle2 = std::thread::spawn(move || {
-        let result = spawn_client(socket_path, vec!["/bin/bash".into(), "-i".into()]);
-        use nix::sys::wait::WaitStatus::*;
-        match result {
-            Err(e) => error!("spawn_client failed: {}", e),
-            Ok(Exited(_, code)) => info!("client exited with status {}", code),
-            Ok(Signaled(_, code, _)) => info!("client killed by signal {:?}", code),
-            _ => info!("client watcher returned with status {:?}", result),
-        }
-    });
+    win.main(event_tx, restart_tx, client_handle.clone(), model);
 
-    win.main(event_tx, model);
-    join_handle1.join().unwrap();
-    join_handle2.join().unwrap();
+    //the window has been closed: stop accepting new connections (the servers
+    //keep listening, but nobody can find them anymore), and tell the client
+    for path in &[&socket_path, &stdio_socket_path] {
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("cannot remove {}: {}", path.to_string_lossy(), e);
+            }
+        }
+    }
+    client_handle.hangup();
+    let _ = shutdown_tx.send(());
+
+    //wait a bounded time for the connections to drain, then escalate to SIGKILL
+    let mut done = done_rx.recv_timeout(SHUTDOWN_TIMEOUT).is_ok();
+    if !done {
+        warn!("connections did not close within {:?}, killing the client", SHUTDOWN_TIMEOUT);
+        client_handle.kill();
+    }
+    //the relays have ended with the client, unless some other process still
+    //holds on to the pseudo-terminal (stopping them closes their connection)
+    if !client_handle.stop_relays(std::time::Instant::now() + KILL_TIMEOUT) {
+        warn!("pseudo-terminal relay did not stop within {:?}, exiting anyway", KILL_TIMEOUT);
+    }
+    if !done {
+        done = done_rx.recv_timeout(KILL_TIMEOUT).is_ok();
+    }
+    if done {
+        let _ = eventloop.join();
+    } else {
+        warn!("connections did not close within {:?} after SIGKILL, exiting anyway", KILL_TIMEOUT);
+    }
 }
 
-fn find_runtime_dir() -> Result<std::path::PathBuf, ()> {
+///How long to wait for connections to drain after the window has been closed.
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+///How long to wait for connections to drain after the client has been killed.
+const KILL_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+fn find_runtime_dir(socket_dir: Option<std::path::PathBuf>) -> Result<std::path::PathBuf, ()> {
+    //a directory given on the command line takes precedence (it belongs to
+    //the user, so we only check it instead of changing its permissions)
+    if let Some(dir) = socket_dir {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            error!("mkdir {}: {}", dir.to_string_lossy(), e);
+            return Err(());
+        }
+        check_dir(&dir, false)?;
+        return Ok(dir);
+    }
+
     //we need XDG_RUNTIME_DIR as the base for our socket path
     let mut runtime_dir = match std::env::var_os("XDG_RUNTIME_DIR") {
         Some(s) => std::path::PathBuf::from(s),
@@ -111,43 +184,64 @@ fn find_runtime_dir() -> Result<std::path::PathBuf, ()> {
         error!("mkdir {}: {}", runtime_dir.to_string_lossy(), e);
         return Err(());
     }
+    check_dir(&runtime_dir, true)?;
 
     Ok(runtime_dir)
 }
 
-use std::ffi::CString;
+///Ensures that only the current user can access the socket directory. If
+///`may_chmod` is true, the permissions are fixed if necessary. Otherwise, a
+///directory that is accessible to other users is an error.
+fn check_dir(dir: &std::path::Path, may_chmod: bool) -> Result<(), ()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
-fn spawn_client(socket_path: std::path::PathBuf, command_and_args: Vec<String>) -> nix::Result<nix::sys::wait::WaitStatus> {
-    //before forking, make all necessary allocations
-    let env: Vec<CString> = std::env::vars()
-        .filter(|(k,_v)| k != "SHELL" && k != "LINES" && k != "COLUMNS")
-        .map(|(k,v)| CString::new(format!("{}={}", k, v)).unwrap())
-        .collect();
-    let args: Vec<CString> = command_and_args.iter().map(|s| CString::new(s.clone()).unwrap()).collect();
-    let command = CString::new(command_and_args[0].clone()).unwrap();
+    let metadata = std::fs::metadata(dir).map_err(|e| error!("stat {}: {}", dir.to_string_lossy(), e))?;
+    if metadata.uid() != nix::unistd::getuid().as_raw() {
+        error!("{} is owned by another user", dir.to_string_lossy());
+        return Err(());
+    }
+    if metadata.mode() & 0o077 != 0 {
+        if !may_chmod {
+            error!("{} is accessible to other users (mode {:o}), refusing to put a socket there", dir.to_string_lossy(), metadata.mode() & 0o777);
+            return Err(());
+        }
+        let perms = std::fs::Permissions::from_mode(0o700);
+        std::fs::set_permissions(dir, perms).map_err(|e| error!("chmod {}: {}", dir.to_string_lossy(), e))?;
+    }
+    Ok(())
+}
 
-    //open stdio for the child process
-    use std::os::unix::io::IntoRawFd;
+///Removes a socket file left behind by a previous 6term process with the same
+///PID. Fails if the file is not a socket or if a server is still listening on it.
+fn remove_stale_socket(socket_path: &std::path::Path) -> Result<(), ()> {
+    use std::os::unix::fs::FileTypeExt;
     use std::os::unix::net::UnixStream;
-    let stream = UnixStream::connect(socket_path).unwrap();
 
-    use nix::unistd::*;
-    match fork()? {
-        ForkResult::Parent { child, .. } => {
-            //wait on child
-            nix::sys::wait::waitpid(child, None)
+    let metadata = match std::fs::symlink_metadata(socket_path) {
+        Ok(m) => m,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            error!("stat {}: {}", socket_path.to_string_lossy(), e);
+            return Err(());
         },
-        ForkResult::Child => {
-            let stream_fd = stream.into_raw_fd();
-            dup2(stream_fd, 0).unwrap_or_else(|_| std::process::exit(200));
-            dup2(stream_fd, 1).unwrap_or_else(|_| std::process::exit(201));
-            dup2(stream_fd, 2).unwrap_or_else(|_| std::process::exit(202));
-            if stream_fd > 2 {
-                close(stream_fd).unwrap_or_else(|_| std::process::exit(203));
-            }
-            execve(&command, &args, &env).unwrap_or_else(|_| std::process::exit(204));
-            //FIXME remove the next line when rustc learns to understand that Void can cast into anything
-            Ok(nix::sys::wait::WaitStatus::StillAlive)
+    };
+    if !metadata.file_type().is_socket() {
+        error!("{} exists and is not a socket", socket_path.to_string_lossy());
+        return Err(());
+    }
+
+    match UnixStream::connect(socket_path) {
+        Ok(_) => {
+            error!("{} is in use by another server", socket_path.to_string_lossy());
+            Err(())
+        },
+        Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            info!("removing stale socket {}", socket_path.to_string_lossy());
+            std::fs::remove_file(socket_path).map_err(|e| error!("rm {}: {}", socket_path.to_string_lossy(), e))
+        },
+        Err(e) => {
+            error!("connect {}: {}", socket_path.to_string_lossy(), e);
+            Err(())
         },
     }
 }
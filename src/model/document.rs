@@ -16,22 +16,159 @@
 *
 *******************************************************************************/
 
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
 use std::sync::{Arc, Mutex};
 
+use unicode_segmentation::UnicodeSegmentation;
+
 use model;
 
+///Limits on how much history is retained in a Document. When a limit is
+///exceeded, the oldest sections are discarded or truncated. `None` means
+///unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct ScrollbackPolicy {
+    pub max_sections: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub max_lines: Option<usize>,
+}
+
+impl Default for ScrollbackPolicy {
+    fn default() -> ScrollbackPolicy {
+        ScrollbackPolicy {
+            max_sections: Some(10000),
+            max_bytes: Some(64 << 20),
+            max_lines: Some(100000),
+        }
+    }
+}
+
+///Lines of user input that have been submitted, but not yet delivered to the
+///server eventloop because its input channel was full.
+pub struct InputQueue {
+    lines: VecDeque<String>,
+}
+
+///When this many lines are waiting for delivery, no further lines can be
+///submitted until the client catches up.
+const INPUT_QUEUE_CAPACITY: usize = 256;
+
+impl InputQueue {
+    fn new() -> InputQueue {
+        InputQueue { lines: VecDeque::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+    pub fn is_full(&self) -> bool {
+        self.lines.len() >= INPUT_QUEUE_CAPACITY
+    }
+    pub fn lines(&self) -> &VecDeque<String> {
+        &self.lines
+    }
+
+    pub fn push(&mut self, line: String) {
+        self.lines.push_back(line);
+    }
+
+    ///Delivers queued lines in order using the given function, until it fails.
+    ///Returns whether all lines were delivered.
+    pub fn flush<F: FnMut(String) -> Result<(), String>>(&mut self, mut deliver: F) -> bool {
+        while let Some(line) = self.lines.pop_front() {
+            if let Err(line) = deliver(line) {
+                self.lines.push_front(line);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+///Lifecycle state of the client program that 6term started.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClientState {
+    Running,
+    Exited,
+    ///The client has exited, and the user may restart it by pressing Enter.
+    ExitedRestartable,
+}
+
+///A signal that the user can send to the client with a control key.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ControlSignal {
+    ///Ctrl+C
+    Interrupt,
+    ///Ctrl+\
+    Quit,
+    ///Ctrl+Z
+    Suspend,
+}
+
+///Delivers a control signal as an event to a VT6-aware client. Returns whether
+///the event could be delivered.
+pub type SignalReceiver = Box<FnMut(ControlSignal) -> bool + Send>;
+
 ///This is the main model object that both the GUI thread and the Tokio
 ///eventloop have access to.
 pub struct Document {
     pub sections: Vec<model::Section>,
+    pub scrollback: ScrollbackPolicy,
+    pub input_queue: InputQueue,
+    pub kill_ring: model::KillRing,
+    pub client_state: ClientState,
+    ///IDs of the server connections that have requested that keystrokes are
+    ///sent to the client directly (through the term.input-immediate property)
+    ///instead of being edited locally.
+    immediate_input_connections: HashSet<u32>,
+    ///IDs of the server connections that have requested that user input is
+    ///not shown (by setting the term.input-echo property to false).
+    no_echo_connections: HashSet<u32>,
+    ///User input that is typed while echo is off. It is kept here instead of
+    ///in the last section, so that it never shows up on screen.
+    concealed_input: String,
+    ///The server connections that have enabled the sig module, so that control
+    ///keys are delivered to them as events instead of Unix signals.
+    signal_receivers: HashMap<u32, SignalReceiver>,
+    ///The alternate screen of a full-screen client program, if it is active.
+    ///It is shown instead of the sections while it exists.
+    pub screen: Option<model::GridSection>,
+    ///The size of the viewport in character cells (columns, rows), as measured
+    ///by the view.
+    screen_size: (usize, usize),
     next_section_id: model::SectionID,
+    ///Whether any history was discarded because of the scrollback policy.
+    trimmed: bool,
+    ///How many sections were discarded because of the scrollback policy. The
+    ///view uses this to notice when it needs to drop its section views.
+    removed_sections: u64,
+    ///The ID for the next server connection (see register_connection()).
+    next_connection_id: u32,
+    ///How many server connections are currently open. On shutdown, 6term waits
+    ///for this to drop to zero.
+    open_connections: usize,
 }
 
 impl Document {
     pub fn new() -> Arc<Mutex<Document>> {
         Arc::new(Mutex::new(Document {
             sections: Vec::new(),
+            scrollback: ScrollbackPolicy::default(),
+            input_queue: InputQueue::new(),
+            kill_ring: model::KillRing::new(),
+            client_state: ClientState::Running,
+            immediate_input_connections: HashSet::new(),
+            no_echo_connections: HashSet::new(),
+            concealed_input: String::new(),
+            signal_receivers: HashMap::new(),
+            screen: None,
+            screen_size: (80, 24),
             next_section_id: model::SectionID::new(),
+            trimmed: false,
+            removed_sections: 0,
+            next_connection_id: 0,
+            open_connections: 0,
         }))
     }
 
@@ -39,4 +176,427 @@ impl Document {
         self.next_section_id.incr();
         model::Section::new(self.next_section_id)
     }
+
+    pub fn is_trimmed(&self) -> bool {
+        self.trimmed
+    }
+    pub fn removed_sections(&self) -> u64 {
+        self.removed_sections
+    }
+
+    ///Whether keystrokes shall be sent to the client directly. This is the
+    ///case when requested by the client, and on the alternate screen since
+    ///full-screen clients generally expect single keystrokes.
+    pub fn wants_immediate_input(&self) -> bool {
+        !self.immediate_input_connections.is_empty() || self.screen.is_some()
+    }
+
+    ///Whether the given server connection has requested immediate input.
+    pub fn has_immediate_input(&self, connection_id: u32) -> bool {
+        self.immediate_input_connections.contains(&connection_id)
+    }
+
+    ///Called when a server connection sets the term.input-immediate property,
+    ///and when it goes away. Immediate input stays active as long as any
+    ///connection requests it.
+    pub fn set_immediate_input(&mut self, connection_id: u32, value: bool) {
+        if value {
+            self.immediate_input_connections.insert(connection_id);
+        } else {
+            self.immediate_input_connections.remove(&connection_id);
+        }
+    }
+
+    ///Whether user input shall be shown while it is being edited. Echo is off
+    ///as long as any connection requests that.
+    pub fn wants_input_echo(&self) -> bool {
+        self.no_echo_connections.is_empty()
+    }
+
+    ///Whether the given server connection has left echo on.
+    pub fn has_input_echo(&self, connection_id: u32) -> bool {
+        !self.no_echo_connections.contains(&connection_id)
+    }
+
+    ///Called when a server connection sets the term.input-echo property, and
+    ///when it goes away. When echo is switched back on, concealed input that
+    ///has not been submitted yet is discarded instead of being revealed.
+    pub fn set_input_echo(&mut self, connection_id: u32, value: bool) {
+        if value {
+            self.no_echo_connections.remove(&connection_id);
+            if self.no_echo_connections.is_empty() {
+                self.concealed_input.clear();
+            }
+        } else {
+            self.no_echo_connections.insert(connection_id);
+        }
+    }
+
+    ///Called when a server connection enables the sig module, and (with None)
+    ///when it goes away.
+    pub fn set_signal_receiver(&mut self, connection_id: u32, receiver: Option<SignalReceiver>) {
+        match receiver {
+            Some(receiver) => self.signal_receivers.insert(connection_id, receiver),
+            None => self.signal_receivers.remove(&connection_id),
+        };
+    }
+
+    ///Delivers a control signal to all connections that have enabled the sig
+    ///module. Returns false if it did not reach any of them, in which case the
+    ///caller shall send a Unix signal instead.
+    pub fn deliver_signal(&mut self, signal: ControlSignal) -> bool {
+        let mut delivered = false;
+        for receiver in self.signal_receivers.values_mut() {
+            delivered |= receiver(signal);
+        }
+        delivered
+    }
+
+    ///Whether the user has typed input that has not been submitted yet.
+    pub fn has_pending_input(&self) -> bool {
+        !self.concealed_input.is_empty() || self.sections.last().map_or(false, |s| s.has_pending_input())
+    }
+
+    pub fn screen_size(&self) -> (usize, usize) {
+        self.screen_size
+    }
+
+    ///Called by the view when the size of the viewport has changed.
+    pub fn set_screen_size(&mut self, width: usize, height: usize) {
+        self.screen_size = (width, height);
+        if let Some(ref mut screen) = self.screen {
+            screen.resize(width, height);
+        }
+    }
+
+    ///Applies an editing action to the pending user input in the last section,
+    ///or to the concealed input while echo is off.
+    pub fn execute_input_action(&mut self, action: model::CursorAction) -> model::CursorActionResult {
+        if !self.wants_input_echo() {
+            return self.execute_concealed_input_action(action);
+        }
+        match self.sections.last_mut() {
+            Some(section) => section.execute_input_action(action, &mut self.kill_ring),
+            None => model::CursorActionResult::Unchanged,
+        }
+    }
+
+    ///Like execute_input_action(), but for concealed input. Since the user
+    ///cannot see where the cursor is, only typing, Backspace and Ctrl+U are
+    ///supported (like in the password prompt of a traditional terminal).
+    ///Nothing visible changes until the line is completed.
+    fn execute_concealed_input_action(&mut self, action: model::CursorAction) -> model::CursorActionResult {
+        use model::CursorAction::*;
+        use model::CursorActionResult::*;
+        match action {
+            Insert(text) => {
+                self.concealed_input.push_str(&text);
+                if self.concealed_input.ends_with('\n') {
+                    return LineCompleted(mem::replace(&mut self.concealed_input, String::new()));
+                }
+            },
+            DeletePreviousGrapheme => {
+                let offset = self.concealed_input.grapheme_indices(true).next_back().map(|(idx, _)| idx);
+                if let Some(offset) = offset {
+                    self.concealed_input.truncate(offset);
+                }
+            },
+            //killed text does not go into the kill ring, where it could be
+            //yanked into view
+            KillToStart => self.concealed_input.clear(),
+            _ => {},
+        }
+        Unchanged
+    }
+
+    ///Processes output from a client program. The `parser` carries state
+    ///between calls, so an escape sequence or UTF-8 character may be split
+    ///across multiple calls. Output goes into the alternate screen while it is
+    ///active, and into the last section otherwise.
+    pub fn append_output(&mut self, parser: &mut model::OutputParser, input: &[u8], output_protected: bool) {
+        let (text, spans) = parser.parse(input, output_protected, &mut self.screen, self.screen_size);
+        if text.is_empty() {
+            return;
+        }
+        if let Some(section) = self.sections.last_mut() {
+            section.append_output(&text, spans);
+        }
+    }
+
+    ///Called by the server for each new connection. Returns an ID that
+    ///identifies the connection in the model (e.g. in set_immediate_input()).
+    pub fn register_connection(&mut self) -> u32 {
+        let id = self.next_connection_id;
+        self.next_connection_id = self.next_connection_id.wrapping_add(1);
+        self.open_connections += 1;
+        id
+    }
+
+    ///Called by the server when a connection is closed. Anything that the
+    ///connection has set up in the model ends with it.
+    pub fn unregister_connection(&mut self, connection_id: u32) {
+        self.open_connections -= 1;
+        self.set_immediate_input(connection_id, false);
+        self.set_input_echo(connection_id, true);
+        self.set_signal_receiver(connection_id, None);
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.open_connections
+    }
+
+    ///Shows the given status message (describing how the client exited) in a
+    ///section of its own.
+    pub fn report_client_exit(&mut self, message: &str, restartable: bool) {
+        let mut section = self.make_section();
+        if restartable {
+            section.append_status(&format!("{} (press Enter to restart)", message));
+            self.client_state = ClientState::ExitedRestartable;
+        } else {
+            section.append_status(message);
+            self.client_state = ClientState::Exited;
+        }
+        self.sections.push(section);
+        //whatever the client has set up is gone with it
+        self.immediate_input_connections.clear();
+        self.no_echo_connections.clear();
+        self.concealed_input.clear();
+        self.signal_receivers.clear();
+        self.screen = None;
+    }
+
+    ///Called when the client has been restarted. Its output goes into a new
+    ///section.
+    pub fn report_client_restart(&mut self) {
+        let section = self.make_section();
+        self.sections.push(section);
+        self.client_state = ClientState::Running;
+    }
+
+    ///Discards or truncates the oldest sections until the scrollback policy is
+    ///satisfied. The last section is never discarded since it receives output
+    ///and input, but it may be truncated.
+    pub fn enforce_scrollback(&mut self) {
+        let policy = self.scrollback;
+
+        if let Some(max) = policy.max_sections {
+            let max = if max == 0 { 1 } else { max };
+            if self.sections.len() > max {
+                let excess = self.sections.len() - max;
+                self.sections.drain(0..excess);
+                self.removed_sections += excess as u64;
+                self.trimmed = true;
+            }
+        }
+
+        if let Some(max) = policy.max_bytes {
+            let mut total: usize = self.sections.iter().map(|s| s.len()).sum();
+            while total > max {
+                let excess = total - max;
+                match self.trim_first_section(excess, |s| s.len(), |s, n| s.truncate_front(n)) {
+                    Some(removed) => total -= removed,
+                    None => break,
+                }
+            }
+        }
+
+        if let Some(max) = policy.max_lines {
+            let mut total: usize = self.sections.iter().map(|s| s.line_count()).sum();
+            while total > max {
+                let excess = total - max;
+                match self.trim_first_section(excess, |s| s.line_count(), |s, n| s.truncate_front_lines(n)) {
+                    Some(removed) => total -= removed,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    ///Reduces the size of the first section by (at least) `excess`, either by
+    ///discarding it entirely or by truncating it. Returns by how much the size
+    ///was reduced, as measured by `size`, or None if nothing could be removed.
+    fn trim_first_section<S, T>(&mut self, excess: usize, size: S, truncate: T) -> Option<usize>
+        where S: Fn(&model::Section) -> usize, T: Fn(&mut model::Section, usize) -> usize
+    {
+        let first_size = match self.sections.first() {
+            Some(s) => size(s),
+            None => return None,
+        };
+        if self.sections.len() > 1 && first_size <= excess {
+            self.sections.remove(0);
+            self.removed_sections += 1;
+            self.trimmed = true;
+            return Some(first_size);
+        }
+
+        let section = &mut self.sections[0];
+        if truncate(section, excess) == 0 {
+            return None;
+        }
+        self.trimmed = true;
+        Some(first_size - size(section))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use model::{CursorAction, KillRing, Style, StyledSpan};
+
+    fn make_document(policy: ScrollbackPolicy, outputs: &[&str]) -> Document {
+        let document = Arc::try_unwrap(Document::new()).ok().unwrap();
+        let mut document = document.into_inner().unwrap();
+        document.scrollback = policy;
+        for output in outputs {
+            let mut section = document.make_section();
+            section.append_output(output, Vec::new());
+            document.sections.push(section);
+        }
+        document
+    }
+
+    ///Returns the full text of each section, and checks that its line count
+    ///matches its text.
+    fn texts(document: &Document) -> Vec<String> {
+        document.sections.iter().map(|section| {
+            let mut text: String = section.chunks().iter().map(|c| c.text()).collect();
+            let line_count = text.matches('\n').count() + section.text()[..section.output_cursor()].matches('\n').count();
+            assert_eq!(section.line_count(), line_count);
+            text.push_str(section.text());
+            assert_eq!(section.len(), text.len());
+            text
+        }).collect()
+    }
+
+    fn policy(max_sections: Option<usize>, max_bytes: Option<usize>, max_lines: Option<usize>) -> ScrollbackPolicy {
+        ScrollbackPolicy { max_sections, max_bytes, max_lines }
+    }
+
+    #[test]
+    fn max_sections() {
+        let mut document = make_document(policy(Some(2), None, None), &["a\n", "b\n", "c\n", "d\n"]);
+        document.enforce_scrollback();
+        assert_eq!(texts(&document), vec!["c\n", "d\n"]);
+        assert_eq!(document.removed_sections(), 2);
+        assert!(document.is_trimmed());
+
+        //the last section is kept in any case
+        document.scrollback.max_sections = Some(0);
+        document.enforce_scrollback();
+        assert_eq!(texts(&document), vec!["d\n"]);
+        assert_eq!(document.removed_sections(), 3);
+    }
+
+    #[test]
+    fn max_bytes() {
+        let mut document = make_document(policy(None, Some(20), None), &["first\n", "second line\nthird line\n", "last\n"]);
+        document.enforce_scrollback();
+        //the first section is removed entirely, the second one is cut after the
+        //line that contains the 20th byte from the end
+        assert_eq!(texts(&document), vec!["third line\n", "last\n"]);
+        assert_eq!(document.removed_sections(), 1);
+        assert!(document.is_trimmed());
+
+        //nothing to do when the limit is not exceeded
+        let mut document = make_document(policy(None, Some(20), None), &["first\n", "last\n"]);
+        document.enforce_scrollback();
+        assert_eq!(texts(&document), vec!["first\n", "last\n"]);
+        assert!(!document.is_trimmed());
+    }
+
+    #[test]
+    fn max_lines() {
+        let mut document = make_document(policy(None, None, Some(3)), &["1\n2\n", "3\n4\n5\n", "6\n"]);
+        document.enforce_scrollback();
+        assert_eq!(texts(&document), vec!["4\n5\n", "6\n"]);
+        assert_eq!(document.removed_sections(), 1);
+        assert!(document.is_trimmed());
+    }
+
+    #[test]
+    fn trim_last_section() {
+        let mut document = make_document(policy(None, None, Some(100)), &[]);
+        let mut section = document.make_section();
+        let bold = Style { bold: true, ..Style::default() };
+        for idx in 0..1000 {
+            section.append_output(&format!("line {:04}\n", idx), vec![StyledSpan { start: 5, end: 9, style: bold }]);
+        }
+        let mut kill_ring = KillRing::new();
+        section.execute_input_action(CursorAction::Insert("echo".into()), &mut kill_ring);
+        assert!(section.chunks().len() >= 2);
+        document.sections.push(section);
+
+        //sealed chunks and the open part are truncated, but the pending input stays
+        document.enforce_scrollback();
+        let expected: String = (900..1000).map(|idx| format!("line {:04}\n", idx)).collect();
+        assert_eq!(texts(&document), vec![expected + "echo"]);
+        assert_eq!(document.removed_sections(), 0);
+        assert!(document.is_trimmed());
+
+        let section = &document.sections[0];
+        assert_eq!(section.line_count(), 100);
+        assert!(section.chunks().is_empty());
+        assert_eq!(&section.text()[section.output_cursor()..], "echo");
+        assert_eq!(section.input_cursor(), section.text().len());
+        assert_eq!(section.spans().len(), 100);
+        assert_eq!(section.spans()[0], StyledSpan { start: 5, end: 9, style: bold });
+
+        //when no line boundary is left, the cut goes through the middle of a
+        //line, but not through a character
+        let mut document = make_document(policy(None, Some(3), None), &["äöü"]);
+        document.enforce_scrollback();
+        assert_eq!(texts(&document), vec!["ü"]);
+    }
+
+    #[test]
+    fn concealed_input() {
+        use model::CursorActionResult::*;
+        let mut document = make_document(policy(None, None, None), &["Password: "]);
+        let conn = document.register_connection();
+        document.set_input_echo(conn, false);
+        assert!(!document.has_input_echo(conn));
+
+        //concealed input never reaches the section
+        for text in &["se", "cre", "x"] {
+            assert!(document.execute_input_action(CursorAction::Insert(text.to_string())) == Unchanged);
+        }
+        document.execute_input_action(CursorAction::DeletePreviousGrapheme);
+        assert!(document.has_pending_input());
+        assert_eq!(texts(&document), vec!["Password: "]);
+        assert!(document.execute_input_action(CursorAction::Insert("t\n".into())) == LineCompleted("secret\n".into()));
+        assert_eq!(texts(&document), vec!["Password: "]);
+
+        //when echo is switched back on, unsubmitted input is discarded
+        document.execute_input_action(CursorAction::Insert("abc".into()));
+        document.unregister_connection(conn);
+        assert!(document.wants_input_echo());
+        assert!(!document.has_pending_input());
+        document.execute_input_action(CursorAction::Insert("d".into()));
+        assert_eq!(texts(&document), vec!["Password: d"]);
+    }
+
+    #[test]
+    fn deliver_signal() {
+        use std::sync::mpsc;
+        let mut document = make_document(policy(None, None, None), &[]);
+        assert!(!document.deliver_signal(ControlSignal::Interrupt));
+
+        //the signal goes to all receivers, and counts as delivered if any of
+        //them accepts it
+        let (tx, rx) = mpsc::channel();
+        let first = document.register_connection();
+        document.set_signal_receiver(first, Some(Box::new(move |signal| tx.send(signal).is_ok())));
+        let second = document.register_connection();
+        document.set_signal_receiver(second, Some(Box::new(|_| false)));
+        assert!(document.deliver_signal(ControlSignal::Quit));
+        assert_eq!(rx.try_recv(), Ok(ControlSignal::Quit));
+
+        document.unregister_connection(first);
+        assert!(!document.deliver_signal(ControlSignal::Suspend));
+        assert!(rx.try_recv().is_err());
+    }
 }
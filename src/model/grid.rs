@@ -0,0 +1,727 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use std::cmp::min;
+
+use unicode_width::UnicodeWidthChar;
+
+use model::Style;
+
+///A single character cell of a GridSection.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cell {
+    pub ch: char,
+    ///Zero-width characters (e.g. combining accents) that were printed after
+    ///`ch`.
+    pub combining: String,
+    ///Set on the second cell of a double-width character. Such a cell is
+    ///covered by the character in the cell before it, and its `ch` is unused.
+    pub continuation: bool,
+    pub style: Style,
+}
+
+impl Cell {
+    fn new(ch: char, style: Style) -> Cell {
+        Cell { ch, combining: String::new(), continuation: false, style }
+    }
+
+    ///An empty cell as left behind by erase operations. Only the background
+    ///color of the current style is retained (like in xterm).
+    fn blank(style: Style) -> Cell {
+        Cell::new(' ', Style { background: style.background, ..Style::default() })
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell::blank(Style::default())
+    }
+}
+
+///Cursor state saved by DECSC (`ESC 7`) and restored by DECRC (`ESC 8`).
+#[derive(Clone, Copy)]
+struct SavedCursor {
+    row: usize,
+    col: usize,
+    style: Style,
+    origin_mode: bool,
+}
+
+///A fixed-size grid of character cells with a cursor, following the screen
+///model of VT100 and xterm. This is used for the alternate screen of
+///full-screen client programs (editors, pagers, etc.), which need cursor
+///addressing instead of the append-only text of a model::Section.
+///
+///All coordinates are 0-based (escape sequences use 1-based coordinates; the
+///conversion happens in the parser).
+pub struct GridSection {
+    width: usize,
+    height: usize,
+    rows: Vec<Vec<Cell>>,
+    cursor_row: usize,
+    cursor_col: usize,
+    ///Set after a character was printed into the last column. The next printed
+    ///character goes into the next line (if autowrap is enabled).
+    wrap_pending: bool,
+    saved_cursor: Option<SavedCursor>,
+    ///First and last row (inclusive) of the scrolling region (DECSTBM).
+    scroll_top: usize,
+    scroll_bottom: usize,
+    ///DECOM: cursor addressing is relative to the scrolling region.
+    origin_mode: bool,
+    ///DECAWM
+    auto_wrap: bool,
+    ///DECTCEM
+    cursor_visible: bool,
+}
+
+impl GridSection {
+    pub fn new(width: usize, height: usize) -> GridSection {
+        let width = width.max(1);
+        let height = height.max(1);
+        GridSection {
+            width, height,
+            rows: vec![vec![Cell::default(); width]; height],
+            cursor_row: 0,
+            cursor_col: 0,
+            wrap_pending: false,
+            saved_cursor: None,
+            scroll_top: 0,
+            scroll_bottom: height - 1,
+            origin_mode: false,
+            auto_wrap: true,
+            cursor_visible: true,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn rows(&self) -> &[Vec<Cell>] {
+        &self.rows
+    }
+    ///Returns the cursor position as (row, column).
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor_row, self.cursor_col)
+    }
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    ///Changes the size of the grid. Content that does not fit anymore is cut
+    ///off at the right and at the top (so that the cursor line stays visible).
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let width = width.max(1);
+        let height = height.max(1);
+        if width == self.width && height == self.height {
+            return;
+        }
+
+        for row in self.rows.iter_mut() {
+            split_wide_char(row, width);
+            row.resize(width, Cell::default());
+        }
+        if self.cursor_row >= height {
+            let excess = self.cursor_row + 1 - height;
+            self.rows.drain(0..excess);
+            self.cursor_row -= excess;
+        }
+        self.rows.resize(height, vec![Cell::default(); width]);
+
+        self.width = width;
+        self.height = height;
+        self.cursor_col = min(self.cursor_col, width - 1);
+        self.wrap_pending = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = height - 1;
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // printing and control characters
+
+    ///Prints a character at the cursor. Double-width characters take up two
+    ///cells, and zero-width characters are attached to the preceding cell.
+    pub fn print(&mut self, ch: char, style: Style) {
+        //a grid with only one column cannot show double-width characters in
+        //full, so they are cut off instead of being dropped
+        let width = match ch.width() {
+            Some(0) => return self.print_combining(ch),
+            Some(2) if self.width > 1 => 2,
+            _ => 1,
+        };
+
+        if self.wrap_pending {
+            self.carriage_return();
+            self.linefeed();
+        }
+        //a double-width character that does not fit into the rest of the line
+        //goes into the next line (or into the last two cells without autowrap)
+        if self.cursor_col + width > self.width {
+            if self.auto_wrap {
+                self.carriage_return();
+                self.linefeed();
+            } else {
+                self.cursor_col = self.width - width;
+            }
+        }
+
+        let col = self.cursor_col;
+        let row = &mut self.rows[self.cursor_row];
+        split_wide_char(row, col);
+        split_wide_char(row, col + width);
+        row[col] = Cell::new(ch, style);
+        if width == 2 {
+            row[col + 1] = Cell { continuation: true, ..Cell::new(' ', style) };
+        }
+
+        if col + width < self.width {
+            self.cursor_col = col + width;
+        } else {
+            self.cursor_col = self.width - 1;
+            self.wrap_pending = self.auto_wrap;
+        }
+    }
+
+    ///Attaches a zero-width character to the character before the cursor. If
+    ///there is none (at the start of a line), it is dropped.
+    fn print_combining(&mut self, ch: char) {
+        //after printing into the last column, the cursor stays on that cell
+        let col = if self.wrap_pending {
+            self.cursor_col
+        } else if self.cursor_col > 0 {
+            self.cursor_col - 1
+        } else {
+            return;
+        };
+        let row = &mut self.rows[self.cursor_row];
+        let col = if row[col].continuation { col - 1 } else { col };
+        row[col].combining.push(ch);
+    }
+
+    pub fn carriage_return(&mut self) {
+        self.cursor_col = 0;
+        self.wrap_pending = false;
+    }
+
+    ///Moves the cursor down by one line, scrolling the scrolling region if the
+    ///cursor is at its bottom (LF, IND).
+    pub fn linefeed(&mut self) {
+        if self.cursor_row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor_row + 1 < self.height {
+            self.cursor_row += 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    ///Moves the cursor up by one line, scrolling the scrolling region if the
+    ///cursor is at its top (RI).
+    pub fn reverse_index(&mut self) {
+        if self.cursor_row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor_row > 0 {
+            self.cursor_row -= 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor_col > 0 {
+            self.cursor_col -= 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    ///Moves the cursor to the next tab stop. Tab stops are fixed at every
+    ///eighth column.
+    pub fn tab(&mut self) {
+        self.cursor_col = min((self.cursor_col / 8 + 1) * 8, self.width - 1);
+        self.wrap_pending = false;
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // cursor movement
+
+    ///Moves the cursor to the given position (CUP). In origin mode, the row is
+    ///relative to the scrolling region and cannot leave it.
+    pub fn goto(&mut self, row: usize, col: usize) {
+        let (top, bottom) = if self.origin_mode {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.height - 1)
+        };
+        self.cursor_row = min(top + row, bottom);
+        self.cursor_col = min(col, self.width - 1);
+        self.wrap_pending = false;
+    }
+
+    ///Moves the cursor to the given column in the current line (CHA).
+    pub fn goto_col(&mut self, col: usize) {
+        self.cursor_col = min(col, self.width - 1);
+        self.wrap_pending = false;
+    }
+
+    ///Moves the cursor to the given row in the current column (VPA).
+    pub fn goto_row(&mut self, row: usize) {
+        let col = self.cursor_col;
+        self.goto(row, col);
+    }
+
+    ///Moves the cursor up (negative) or down (positive) by the given number of
+    ///lines (CUU, CUD). The cursor stops at the margins of the scrolling region
+    ///if it starts out inside of it.
+    pub fn move_vertically(&mut self, delta: isize) {
+        let (top, bottom) = if self.cursor_row >= self.scroll_top && self.cursor_row <= self.scroll_bottom {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.height - 1)
+        };
+        let row = self.cursor_row as isize + delta;
+        self.cursor_row = row.max(top as isize).min(bottom as isize) as usize;
+        self.wrap_pending = false;
+    }
+
+    ///Moves the cursor left (negative) or right (positive) by the given number
+    ///of columns (CUB, CUF).
+    pub fn move_horizontally(&mut self, delta: isize) {
+        let col = self.cursor_col as isize + delta;
+        self.cursor_col = col.max(0).min(self.width as isize - 1) as usize;
+        self.wrap_pending = false;
+    }
+
+    ///DECSC
+    pub fn save_cursor(&mut self, style: Style) {
+        self.saved_cursor = Some(SavedCursor {
+            row: self.cursor_row,
+            col: self.cursor_col,
+            style,
+            origin_mode: self.origin_mode,
+        });
+    }
+
+    ///DECRC. Returns the style that was saved along with the cursor position.
+    pub fn restore_cursor(&mut self) -> Option<Style> {
+        let saved = self.saved_cursor?;
+        self.cursor_row = min(saved.row, self.height - 1);
+        self.cursor_col = min(saved.col, self.width - 1);
+        self.origin_mode = saved.origin_mode;
+        self.wrap_pending = false;
+        Some(saved.style)
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // erasing
+
+    ///ED: 0 = from cursor to end of screen, 1 = from start of screen to cursor,
+    ///2 (or 3) = entire screen.
+    pub fn erase_in_display(&mut self, mode: i64, style: Style) {
+        let blank = Cell::blank(style);
+        let row = self.cursor_row;
+        match mode {
+            0 => {
+                self.erase_in_line(0, style);
+                for r in self.rows.iter_mut().skip(row + 1) {
+                    fill(r, &blank);
+                }
+            },
+            1 => {
+                for r in self.rows.iter_mut().take(row) {
+                    fill(r, &blank);
+                }
+                self.erase_in_line(1, style);
+            },
+            2 | 3 => {
+                for r in self.rows.iter_mut() {
+                    fill(r, &blank);
+                }
+            },
+            _ => info!("unknown ED mode: {}", mode),
+        }
+        self.wrap_pending = false;
+    }
+
+    ///EL: 0 = from cursor to end of line, 1 = from start of line to cursor, 2 =
+    ///entire line.
+    pub fn erase_in_line(&mut self, mode: i64, style: Style) {
+        let blank = Cell::blank(style);
+        let col = self.cursor_col;
+        let row = &mut self.rows[self.cursor_row];
+        match mode {
+            0 => {
+                split_wide_char(row, col);
+                fill(&mut row[col..], &blank);
+            },
+            1 => {
+                split_wide_char(row, col + 1);
+                fill(&mut row[0..col + 1], &blank);
+            },
+            2 => fill(row, &blank),
+            _ => info!("unknown EL mode: {}", mode),
+        }
+        self.wrap_pending = false;
+    }
+
+    ///ECH: Erases the given number of characters starting at the cursor.
+    pub fn erase_chars(&mut self, count: usize, style: Style) {
+        let col = self.cursor_col;
+        let end = min(col + count, self.width);
+        let row = &mut self.rows[self.cursor_row];
+        split_wide_char(row, col);
+        split_wide_char(row, end);
+        fill(&mut row[col..end], &Cell::blank(style));
+        self.wrap_pending = false;
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // inserting and deleting
+
+    ///ICH: Inserts blank characters at the cursor. Characters at the end of the
+    ///line are lost.
+    pub fn insert_chars(&mut self, count: usize, style: Style) {
+        let width = self.width;
+        let col = self.cursor_col;
+        let count = min(count, width - col);
+        let row = &mut self.rows[self.cursor_row];
+        split_wide_char(row, col);
+        split_wide_char(row, width - count);
+        row.truncate(width - count);
+        for _ in 0..count {
+            row.insert(col, Cell::blank(style));
+        }
+        self.wrap_pending = false;
+    }
+
+    ///DCH: Deletes characters at the cursor. The rest of the line moves left.
+    pub fn delete_chars(&mut self, count: usize, style: Style) {
+        let col = self.cursor_col;
+        let count = min(count, self.width - col);
+        let row = &mut self.rows[self.cursor_row];
+        split_wide_char(row, col);
+        split_wide_char(row, col + count);
+        row.drain(col..col + count);
+        for _ in 0..count {
+            row.push(Cell::blank(style));
+        }
+        self.wrap_pending = false;
+    }
+
+    ///IL: Inserts blank lines at the cursor, moving the following lines of the
+    ///scrolling region down. Does nothing if the cursor is outside the
+    ///scrolling region.
+    pub fn insert_lines(&mut self, count: usize) {
+        if self.cursor_row < self.scroll_top || self.cursor_row > self.scroll_bottom {
+            return;
+        }
+        let (row, bottom) = (self.cursor_row, self.scroll_bottom);
+        self.shift_down(row, bottom, count);
+        self.cursor_col = 0;
+        self.wrap_pending = false;
+    }
+
+    ///DL: Deletes lines at the cursor, moving the following lines of the
+    ///scrolling region up. Does nothing if the cursor is outside the scrolling
+    ///region.
+    pub fn delete_lines(&mut self, count: usize) {
+        if self.cursor_row < self.scroll_top || self.cursor_row > self.scroll_bottom {
+            return;
+        }
+        let (row, bottom) = (self.cursor_row, self.scroll_bottom);
+        self.shift_up(row, bottom, count);
+        self.cursor_col = 0;
+        self.wrap_pending = false;
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // scrolling
+
+    ///SU: Scrolls the contents of the scrolling region up.
+    pub fn scroll_up(&mut self, count: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        self.shift_up(top, bottom, count);
+    }
+
+    ///SD: Scrolls the contents of the scrolling region down.
+    pub fn scroll_down(&mut self, count: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        self.shift_down(top, bottom, count);
+    }
+
+    ///DECSTBM: Sets the scrolling region to the given rows (inclusive), or
+    ///resets it to the full screen if the range is invalid. The cursor moves to
+    ///the home position.
+    pub fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        if top < bottom && bottom < self.height {
+            self.scroll_top = top;
+            self.scroll_bottom = bottom;
+        } else {
+            self.scroll_top = 0;
+            self.scroll_bottom = self.height - 1;
+        }
+        self.goto(0, 0);
+    }
+
+    ///Moves rows `top..=bottom` up by `count`, filling in blank rows at the bottom.
+    fn shift_up(&mut self, top: usize, bottom: usize, count: usize) {
+        let count = min(count, bottom + 1 - top);
+        let width = self.width;
+        self.rows.drain(top..top + count);
+        for _ in 0..count {
+            self.rows.insert(bottom + 1 - count, vec![Cell::default(); width]);
+        }
+    }
+
+    ///Moves rows `top..=bottom` down by `count`, filling in blank rows at the top.
+    fn shift_down(&mut self, top: usize, bottom: usize, count: usize) {
+        let count = min(count, bottom + 1 - top);
+        let width = self.width;
+        self.rows.drain(bottom + 1 - count..bottom + 1);
+        for _ in 0..count {
+            self.rows.insert(top, vec![Cell::default(); width]);
+        }
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // modes
+
+    pub fn set_origin_mode(&mut self, value: bool) {
+        self.origin_mode = value;
+        self.goto(0, 0);
+    }
+    pub fn set_auto_wrap(&mut self, value: bool) {
+        self.auto_wrap = value;
+        self.wrap_pending = false;
+    }
+    pub fn set_cursor_visible(&mut self, value: bool) {
+        self.cursor_visible = value;
+    }
+}
+
+fn fill(cells: &mut [Cell], cell: &Cell) {
+    for c in cells.iter_mut() {
+        *c = cell.clone();
+    }
+}
+
+///Erases the double-width character that straddles the boundary between the
+///cells `col - 1` and `col` (if any). This is called before either half is
+///overwritten or moved, so that no half of a character is left behind.
+fn split_wide_char(row: &mut [Cell], col: usize) {
+    if col > 0 && col < row.len() && row[col].continuation {
+        let blank = Cell::blank(row[col - 1].style);
+        row[col - 1] = blank.clone();
+        row[col] = blank;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::OutputParser;
+
+    ///Runs the given output through a parser, starting out on a fresh
+    ///alternate screen with 10 columns and 5 rows.
+    fn parse(input: &[u8]) -> GridSection {
+        let mut parser = OutputParser::new();
+        let mut screen = None;
+        parser.parse(b"\x1b[?1049h", false, &mut screen, (10, 5));
+        let (text, _) = parser.parse(input, false, &mut screen, (10, 5));
+        assert_eq!(text, "");
+        screen.expect("alternate screen is not active")
+    }
+
+    ///Returns the contents of the grid as one string per row, with trailing
+    ///whitespace removed.
+    fn rows(grid: &GridSection) -> Vec<String> {
+        grid.rows().iter().map(|row| {
+            let mut text = String::new();
+            for cell in row.iter().filter(|cell| !cell.continuation) {
+                text.push(cell.ch);
+                text.push_str(&cell.combining);
+            }
+            text.trim_right().to_string()
+        }).collect()
+    }
+
+    ///Output that fills the screen with one letter per row, and puts the
+    ///cursor at row 2, column 4.
+    const FILLED: &[u8] = b"aaaaaaaaaa\r\nbbbbbbbbbb\r\ncccccccccc\r\ndddddddddd\r\neeeeeeeeee\x1b[3;5H";
+
+    fn filled_then(input: &[u8]) -> Vec<String> {
+        let mut bytes = FILLED.to_vec();
+        bytes.extend_from_slice(input);
+        rows(&parse(&bytes))
+    }
+
+    #[test]
+    fn cursor_position() {
+        let grid = parse(b"\x1b[2;3Hx\x1b[Hy");
+        assert_eq!(rows(&grid), vec!["y", "  x", "", "", ""]);
+        assert_eq!(grid.cursor(), (0, 1));
+
+        //coordinates are clamped to the screen
+        let grid = parse(b"\x1b[99;99Hz");
+        assert_eq!(rows(&grid)[4], "         z");
+        assert_eq!(grid.cursor(), (4, 9));
+
+        //in origin mode, rows are relative to the scrolling region
+        let grid = parse(b"\x1b[2;4r\x1b[?6h\x1b[2;1Hx\x1b[9;1Hy");
+        assert_eq!(rows(&grid), vec!["", "", "x", "y", ""]);
+    }
+
+    #[test]
+    fn erase_in_display() {
+        assert_eq!(filled_then(b"\x1b[J"), vec!["aaaaaaaaaa", "bbbbbbbbbb", "cccc", "", ""]);
+        assert_eq!(filled_then(b"\x1b[1J"), vec!["", "", "     ccccc", "dddddddddd", "eeeeeeeeee"]);
+        assert_eq!(filled_then(b"\x1b[2J"), vec!["", "", "", "", ""]);
+    }
+
+    #[test]
+    fn erase_in_line() {
+        let others = |row: &str| vec!["aaaaaaaaaa".to_string(), "bbbbbbbbbb".into(), row.into(), "dddddddddd".into(), "eeeeeeeeee".into()];
+        assert_eq!(filled_then(b"\x1b[K"), others("cccc"));
+        assert_eq!(filled_then(b"\x1b[1K"), others("     ccccc"));
+        assert_eq!(filled_then(b"\x1b[2K"), others(""));
+        assert_eq!(filled_then(b"\x1b[3X"), others("cccc   ccc"));
+    }
+
+    #[test]
+    fn scroll_region() {
+        //a linefeed at the bottom of the region only scrolls the region
+        assert_eq!(filled_then(b"\x1b[2;4r\x1b[4;1H\nx"), vec!["aaaaaaaaaa", "cccccccccc", "dddddddddd", "x", "eeeeeeeeee"]);
+        let grid = parse(b"\x1b[2;4r\x1b[4;1H\nx");
+        assert_eq!(grid.cursor(), (3, 1));
+
+        //reverse index at the top of the region scrolls it down
+        assert_eq!(filled_then(b"\x1b[2;4r\x1b[2;1H\x1bMx"), vec!["aaaaaaaaaa", "x", "bbbbbbbbbb", "cccccccccc", "eeeeeeeeee"]);
+
+        //an invalid region resets it to the full screen
+        let grid = parse(b"\x1b[4;2r\x1b[5;1H\nx");
+        assert_eq!(rows(&grid), vec!["", "", "", "", "x"]);
+    }
+
+    #[test]
+    fn scroll_up_and_down() {
+        assert_eq!(filled_then(b"\x1b[2S"), vec!["cccccccccc", "dddddddddd", "eeeeeeeeee", "", ""]);
+        assert_eq!(filled_then(b"\x1b[2T"), vec!["", "", "aaaaaaaaaa", "bbbbbbbbbb", "cccccccccc"]);
+        assert_eq!(filled_then(b"\x1b[2;4r\x1b[S"), vec!["aaaaaaaaaa", "cccccccccc", "dddddddddd", "", "eeeeeeeeee"]);
+        assert_eq!(filled_then(b"\x1b[2;4r\x1b[9T"), vec!["aaaaaaaaaa", "", "", "", "eeeeeeeeee"]);
+    }
+
+    #[test]
+    fn insert_and_delete_lines() {
+        assert_eq!(filled_then(b"\x1b[L"), vec!["aaaaaaaaaa", "bbbbbbbbbb", "", "cccccccccc", "dddddddddd"]);
+        assert_eq!(filled_then(b"\x1b[2M"), vec!["aaaaaaaaaa", "bbbbbbbbbb", "eeeeeeeeee", "", ""]);
+        //only the scrolling region is affected
+        assert_eq!(filled_then(b"\x1b[1;4r\x1b[3;1H\x1b[L"), vec!["aaaaaaaaaa", "bbbbbbbbbb", "", "cccccccccc", "eeeeeeeeee"]);
+        //outside the scrolling region, nothing happens
+        assert_eq!(filled_then(b"\x1b[1;3r\x1b[5;1H\x1b[M"), rows(&parse(FILLED)));
+    }
+
+    #[test]
+    fn autowrap() {
+        //the cursor stays in the last column until the next character arrives
+        let grid = parse(b"0123456789");
+        assert_eq!(grid.cursor(), (0, 9));
+        let grid = parse(b"0123456789ab");
+        assert_eq!(rows(&grid), vec!["0123456789", "ab", "", "", ""]);
+        assert_eq!(grid.cursor(), (1, 2));
+
+        //wrapping at the bottom scrolls the screen
+        let grid = parse(b"\x1b[5;9Habc");
+        assert_eq!(rows(&grid), vec!["", "", "", "        ab", "c"]);
+
+        //without autowrap, the last column is overwritten
+        let grid = parse(b"\x1b[?7l0123456789ab");
+        assert_eq!(rows(&grid), vec!["012345678b", "", "", "", ""]);
+        assert_eq!(grid.cursor(), (0, 9));
+    }
+
+    #[test]
+    fn alternate_screen() {
+        let mut parser = OutputParser::new();
+        let mut screen = None;
+        let (text, _) = parser.parse(b"before\n\x1b[?1049hon screen", false, &mut screen, (10, 5));
+        assert_eq!(text, "before\n");
+        assert_eq!(rows(screen.as_ref().unwrap())[0], "on screen");
+
+        //the screen is resized along with the view
+        screen.as_mut().unwrap().resize(4, 2);
+        assert_eq!(rows(screen.as_ref().unwrap()), vec!["on s", ""]);
+
+        //leaving the alternate screen discards it, and output goes into the
+        //section again
+        let (text, _) = parser.parse(b"\x1b[?1049lafter\n", false, &mut screen, (10, 5));
+        assert_eq!(text, "after\n");
+        assert!(screen.is_none());
+
+        //entering it again starts out blank
+        parser.parse(b"\x1b[?1049h", false, &mut screen, (10, 5));
+        assert_eq!(rows(screen.as_ref().unwrap()), vec!["", "", "", "", ""]);
+    }
+
+    #[test]
+    fn wide_characters() {
+        let grid = parse("a漢b".as_bytes());
+        assert_eq!(rows(&grid)[0], "a漢b");
+        assert_eq!(grid.cursor(), (0, 4));
+        assert!(grid.rows()[0][2].continuation);
+
+        //a double-width character that does not fit goes into the next line
+        let grid = parse("\x1b[1;10H漢".as_bytes());
+        assert_eq!(rows(&grid), vec!["", "漢", "", "", ""]);
+        assert_eq!(grid.cursor(), (1, 2));
+        let grid = parse("\x1b[1;9H漢".as_bytes());
+        assert_eq!(rows(&grid), vec!["        漢", "", "", "", ""]);
+        assert_eq!(grid.cursor(), (0, 9));
+
+        //overwriting either half erases the whole character
+        let grid = parse("漢字\x1b[1;2Hx".as_bytes());
+        assert_eq!(rows(&grid)[0], " x字");
+        let grid = parse("漢字\x1b[1;2H語".as_bytes());
+        assert_eq!(rows(&grid)[0], " 語");
+        assert!(grid.rows()[0].iter().skip(3).all(|cell| !cell.continuation));
+
+        //so does erasing or deleting a part of it
+        let grid = parse("漢字\x1b[1;2H\x1b[K".as_bytes());
+        assert_eq!(rows(&grid)[0], "");
+        let grid = parse("漢字\x1b[1;2H\x1b[P".as_bytes());
+        assert_eq!(rows(&grid)[0], " 字");
+        assert!(!grid.rows()[0][1].continuation);
+    }
+
+    #[test]
+    fn zero_width_characters() {
+        let grid = parse("e\u{301}x".as_bytes());
+        assert_eq!(grid.rows()[0][0].combining, "\u{301}");
+        assert_eq!(rows(&grid)[0], "e\u{301}x");
+        assert_eq!(grid.cursor(), (0, 2));
+
+        //attached to a double-width character, and to the last column
+        let grid = parse("漢\u{301}\x1b[1;10Hy\u{302}".as_bytes());
+        assert_eq!(grid.rows()[0][0].combining, "\u{301}");
+        assert_eq!(grid.rows()[0][9].combining, "\u{302}");
+
+        //dropped at the start of a line
+        let grid = parse("\u{301}".as_bytes());
+        assert_eq!(rows(&grid)[0], "");
+        assert_eq!(grid.cursor(), (0, 0));
+    }
+}
@@ -17,7 +17,11 @@
 *******************************************************************************/
 
 pub mod document;
+pub mod grid;
 pub mod section;
+pub mod style;
 
 pub use self::document::*;
+pub use self::grid::*;
 pub use self::section::*;
+pub use self::style::*;
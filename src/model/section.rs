@@ -16,15 +16,37 @@
 *
 *******************************************************************************/
 
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::mem;
+
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
 use vte;
 
+use model::{GridSection, Style, StyledSpan};
+
+///An editing action on the pending user input. Cursor motion and deletion
+///work on extended grapheme clusters, i.e. on what the user perceives as
+///single characters.
 pub enum CursorAction {
     Insert(String),
-    //TODO replace "Char" by "GraphemeCluster" or sth like that
-    DeletePreviousChar, //Backspace key
-    DeleteNextChar,     //Delete key
-    GotoPreviousChar,   //Left arrow key
-    GotoNextChar,       //Right arrow key
+    DeletePreviousGrapheme, //Backspace key
+    DeleteNextGrapheme,     //Delete key
+    GotoPreviousGrapheme,   //Left arrow key
+    GotoNextGrapheme,       //Right arrow key
+    GotoStart,              //Home key, Ctrl+A
+    GotoEnd,                //End key, Ctrl+E
+    GotoPreviousWord,       //Ctrl+Left, Alt+B
+    GotoNextWord,           //Ctrl+Right, Alt+F
+    DeletePreviousWord,     //Ctrl+W, Alt+Backspace (kills up to the previous whitespace)
+    DeleteNextWord,         //Alt+D (kills to the end of the current or next word)
+    KillToStart,            //Ctrl+U
+    KillToEnd,              //Ctrl+K
+    Yank,                   //Ctrl+Y
+    YankPop,                //Alt+Y (replaces the text just yanked with an older kill)
+    TransposeGraphemes,     //Ctrl+T
+    Undo,                   //Ctrl+_, Ctrl+/
+    Redo,                   //Ctrl+Shift+Z
 }
 
 #[derive(PartialEq,Eq)]
@@ -34,6 +56,84 @@ pub enum CursorActionResult {
     LineCompleted(String),
 }
 
+///When the kill ring contains this many entries, the oldest one is dropped.
+const KILL_RING_SIZE: usize = 16;
+
+///Text removed by kill commands (Ctrl+U, Ctrl+K, Ctrl+W, Alt+D), most recent
+///first. This is shared by all sections, so that text killed in one input line
+///can be yanked into the next one.
+pub struct KillRing {
+    entries: VecDeque<String>,
+    ///Index of the entry that was yanked most recently.
+    yank_index: usize,
+}
+
+impl KillRing {
+    pub fn new() -> KillRing {
+        KillRing { entries: VecDeque::new(), yank_index: 0 }
+    }
+
+    fn push(&mut self, text: String) {
+        self.entries.push_front(text);
+        self.entries.truncate(KILL_RING_SIZE);
+        self.yank_index = 0;
+    }
+
+    ///Adds text to the most recent entry (for consecutive kills).
+    fn extend(&mut self, text: &str, prepend: bool) {
+        if self.entries.is_empty() {
+            self.push(text.to_string());
+            return;
+        }
+        let entry = self.entries.front_mut().unwrap();
+        if prepend {
+            entry.insert_str(0, text);
+        } else {
+            entry.push_str(text);
+        }
+        self.yank_index = 0;
+    }
+
+    ///Returns the most recent entry.
+    fn current(&mut self) -> Option<String> {
+        self.yank_index = 0;
+        self.entries.front().cloned()
+    }
+
+    ///Returns the entry before the one that was yanked most recently.
+    fn rotate(&mut self) -> Option<String> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        self.yank_index = (self.yank_index + 1) % self.entries.len();
+        self.entries.get(self.yank_index).cloned()
+    }
+}
+
+///When more undo steps are recorded, the oldest ones are dropped.
+const UNDO_LIMIT: usize = 100;
+
+///The state of the pending user input, as recorded for undo and redo. This is
+///relative to the output cursor, since output may arrive between edits.
+struct InputSnapshot {
+    input: String,
+    cursor: usize,
+}
+
+///The kind of the previous input action, as far as it affects the next one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LastEdit {
+    Other,
+    ///Typed text, which is undone as a whole.
+    Typing,
+    ///A kill, which is extended by further kills instead of starting a new
+    ///kill ring entry.
+    Kill,
+    ///A yank (offset relative to the output cursor, and length), which can be
+    ///replaced by YankPop.
+    Yank(usize, usize),
+}
+
 ///Unique identifier for a section. This is a separate type to ensure that it is
 ///only generated by Document.make_section().
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
@@ -49,10 +149,49 @@ impl SectionID {
     }
 }
 
+///When the unsealed output of a section grows beyond this many bytes, it is
+///sealed into chunks of at most this size.
+const CHUNK_SIZE: usize = 4096;
+
+///A piece of a section's output, usually consisting of complete lines (only
+///lines longer than CHUNK_SIZE are split across chunks, see
+///find_seal_offset()). Once sealed, chunks
+///are never modified, so the view only needs to lay them out once.
+pub struct Chunk {
+    ///Unique within the section. Chunk IDs increase monotonically.
+    id: u64,
+    text: String,
+    ///Styled ranges of `self.text` (see Section::spans).
+    spans: Vec<StyledSpan>,
+    line_count: usize,
+}
+
+impl Chunk {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn text(&self) -> &str {
+        self.text.as_str()
+    }
+    pub fn spans(&self) -> &[StyledSpan] {
+        &self.spans
+    }
+}
+
 ///A section is some amount of text that appears on screen, starting at the
 ///beginning of a line and ending at the end of a line.
+///
+///The text of a section is stored as a list of sealed chunks, followed by the
+///open part (`self.text`) that output is appended to and that contains the
+///pending user input. This keeps the cost of appending output independent of
+///how much output the section has already received.
 pub struct Section {
     id: SectionID,
+    chunks: VecDeque<Chunk>,
+    next_chunk_id: u64,
+    ///Total length of all texts in `self.chunks`.
+    chunks_len: usize,
+    ///The open part of this section, following the sealed chunks.
     text: String,
     ///Index into self.text where output from client programs will be appended. Everything before
     ///this cursor is client output, everything after this cursor is user input not yet submitted
@@ -61,9 +200,21 @@ pub struct Section {
     ///Index into self.text where user input is being inserted. This is always trailing the
     ///self.output_cursor (i.e., `self.output_cursor <= self.input_cursor`); see above for details.
     input_cursor: usize,
+    ///Ranges of self.text that are rendered with a non-default style, in
+    ///ascending order and non-overlapping. These only ever cover text before
+    ///`self.output_cursor`, i.e. client output and submitted user input.
+    spans: Vec<StyledSpan>,
+    ///Number of line breaks in the sealed chunks and in the text before
+    ///`self.output_cursor`.
+    line_count: usize,
     ///This counter increases whenever this section is changed. It is used to
     ///indicate to the view when re-layouting is necessary.
     generation: u64,
+    ///Previous states of the pending user input, most recent last.
+    undo_stack: Vec<InputSnapshot>,
+    ///States of the pending user input that were undone, most recent last.
+    redo_stack: Vec<InputSnapshot>,
+    last_edit: LastEdit,
 }
 
 impl Section {
@@ -71,119 +222,777 @@ impl Section {
     pub fn new(id: SectionID) -> Section {
         Section {
             id: id,
+            chunks: VecDeque::new(),
+            next_chunk_id: 0,
+            chunks_len: 0,
             text: String::new(),
             output_cursor: 0,
             input_cursor: 0,
+            spans: Vec::new(),
+            line_count: 0,
             generation: 0,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            last_edit: LastEdit::Other,
         }
     }
 
     pub fn id(&self) -> SectionID {
         self.id
     }
+    pub fn chunks(&self) -> &VecDeque<Chunk> {
+        &self.chunks
+    }
+    ///Returns the open part of the section that follows the sealed chunks.
     pub fn text(&self) -> &str {
         self.text.as_str()
     }
+    pub fn spans(&self) -> &[StyledSpan] {
+        &self.spans
+    }
+    ///Returns the total length of the section's text in bytes.
+    pub fn len(&self) -> usize {
+        self.chunks_len + self.text.len()
+    }
+    pub fn line_count(&self) -> usize {
+        self.line_count
+    }
+    pub fn output_cursor(&self) -> usize {
+        self.output_cursor
+    }
     pub fn input_cursor(&self) -> usize {
         self.input_cursor
     }
     pub fn generation(&self) -> u64 {
         self.generation
     }
+    ///Whether the user has typed input that has not been submitted yet.
+    pub fn has_pending_input(&self) -> bool {
+        self.output_cursor < self.text.len()
+    }
 
-    ///Appends additional output to this section.
-    pub fn append_output(&mut self, input: &[u8], output_protected: bool) {
-        let mut parser = vte::Parser::new();
-        let mut performer = ANSIPerformer::new(output_protected);
-
-        for byte in input {
-            parser.advance(&mut performer, *byte);
+    ///Appends a message from the terminal itself (not from a client program),
+    ///e.g. the exit status of the client. It is shown on a line of its own.
+    pub fn append_status(&mut self, message: &str) {
+        let mut text = String::new();
+        if !self.is_at_line_start() {
+            text.push('\n');
         }
+        let start = text.len();
+        text.push_str(message);
+        let end = text.len();
+        text.push('\n');
 
-        let text = performer.string;
-        self.text.insert_str(self.output_cursor, &text);
+        let style = Style { italic: true, dim: true, ..Style::default() };
+        self.append_output(&text, vec![StyledSpan { start, end, style }]);
+    }
+
+    ///Appends output (as decoded by an OutputParser) to this section. It is
+    ///inserted at the output cursor. The spans are relative to `text`.
+    pub fn append_output(&mut self, text: &str, spans: Vec<StyledSpan>) {
+        self.text.insert_str(self.output_cursor, text);
+        let offset = self.output_cursor;
+        for span in spans {
+            self.push_span(StyledSpan { start: span.start + offset, end: span.end + offset, style: span.style });
+        }
         let len = text.len();
         self.input_cursor += len;
         self.output_cursor += len;
+        self.line_count += count_line_breaks(text);
         self.generation += 1;
+        self.seal_chunk_if_needed();
+    }
+
+    ///Whether the output so far ends with a line break (or is empty).
+    fn is_at_line_start(&self) -> bool {
+        if self.output_cursor > 0 {
+            return self.text[..self.output_cursor].ends_with('\n');
+        }
+        self.chunks.back().map_or(true, |chunk| chunk.text.ends_with('\n'))
+    }
+
+    ///Moves output from the start of `self.text` into new chunks until less
+    ///than CHUNK_SIZE bytes of output are left. This keeps the open part
+    ///small even when a large amount of output arrives at once.
+    fn seal_chunk_if_needed(&mut self) {
+        if self.output_cursor < CHUNK_SIZE {
+            return;
+        }
+
+        //all chunks are cut out before the rest of the text is moved, so that
+        //a large append is not copied once per chunk
+        let mut spans: VecDeque<StyledSpan> = self.spans.drain(..).collect();
+        let mut start = 0;
+        while self.output_cursor - start >= CHUNK_SIZE {
+            let end = start + find_seal_offset(&self.text[start..]);
+            let mut chunk_spans = Vec::new();
+            while let Some(span) = spans.pop_front() {
+                if span.start >= end {
+                    spans.push_front(span);
+                    break;
+                }
+                chunk_spans.push(StyledSpan { start: span.start - start, end: min(span.end, end) - start, style: span.style });
+                if span.end > end {
+                    spans.push_front(StyledSpan { start: end, end: span.end, style: span.style });
+                    break;
+                }
+            }
+
+            let text = self.text[start..end].to_string();
+            self.chunks_len += text.len();
+            self.chunks.push_back(Chunk {
+                id: self.next_chunk_id,
+                line_count: count_line_breaks(&text),
+                text: text,
+                spans: chunk_spans,
+            });
+            self.next_chunk_id += 1;
+            start = end;
+        }
+
+        self.text.drain(0..start);
+        self.spans = spans.into_iter()
+            .map(|span| StyledSpan { start: span.start - start, end: span.end - start, style: span.style })
+            .collect();
+        self.output_cursor -= start;
+        self.input_cursor -= start;
+    }
+
+    ///Removes at least the given number of bytes from the start of this
+    ///section. Sealed chunks are only removed as a whole. Otherwise, the cut is
+    ///extended to the next line boundary if possible. Pending user input is
+    ///never removed. Returns the number of bytes removed.
+    pub fn truncate_front(&mut self, bytes: usize) -> usize {
+        let mut removed = 0;
+        while removed < bytes {
+            match self.remove_first_chunk() {
+                Some(chunk) => removed += chunk.text.len(),
+                None => break,
+            }
+        }
+        if removed >= bytes {
+            return removed;
+        }
+
+        let bytes = bytes - removed;
+        let output = &self.text.as_bytes()[0..self.output_cursor];
+        if bytes >= output.len() {
+            let len = output.len();
+            return removed + self.remove_front(len);
+        }
+        let offset = match output[bytes-1..].iter().position(|&b| b == b'\n') {
+            Some(pos) => bytes + pos,
+            //no line boundary in sight -> cut in the middle of the line
+            None => {
+                let mut offset = bytes;
+                while !self.text.is_char_boundary(offset) {
+                    offset += 1;
+                }
+                offset
+            },
+        };
+        removed + self.remove_front(offset)
+    }
+
+    ///Removes at least the given number of lines from the start of this
+    ///section. Sealed chunks are only removed as a whole. Pending user input is
+    ///never removed. Returns the number of bytes removed.
+    pub fn truncate_front_lines(&mut self, lines: usize) -> usize {
+        let mut removed_lines = 0;
+        let mut removed = 0;
+        while removed_lines < lines {
+            match self.remove_first_chunk() {
+                Some(chunk) => {
+                    removed_lines += chunk.line_count;
+                    removed += chunk.text.len();
+                },
+                None => break,
+            }
+        }
+        if removed_lines >= lines {
+            return removed;
+        }
+
+        let offset = self.text.as_bytes()[0..self.output_cursor].iter()
+            .enumerate()
+            .filter(|&(_, &b)| b == b'\n')
+            .map(|(idx, _)| idx + 1)
+            .take(lines - removed_lines)
+            .last()
+            .unwrap_or(0);
+        removed + self.remove_front(offset)
     }
 
-    ///Returns whether the text in this section has changed.
-    pub fn execute_input_action(&mut self, action: CursorAction) -> CursorActionResult {
-        let result = self.execute_input_action_priv(action);
+    fn remove_first_chunk(&mut self) -> Option<Chunk> {
+        let chunk = self.chunks.pop_front()?;
+        self.chunks_len -= chunk.text.len();
+        self.line_count -= chunk.line_count;
+        self.generation += 1;
+        Some(chunk)
+    }
+
+    ///Removes text from the start of `self.text`.
+    fn remove_front(&mut self, offset: usize) -> usize {
+        if offset == 0 {
+            return 0;
+        }
+        self.line_count -= count_line_breaks(&self.text[0..offset]);
+        self.text.drain(0..offset);
+        self.output_cursor -= offset;
+        self.input_cursor -= offset;
+        self.spans.retain(|span| span.end > offset);
+        for span in self.spans.iter_mut() {
+            span.start = span.start.saturating_sub(offset);
+            span.end -= offset;
+        }
+        self.generation += 1;
+        offset
+    }
+
+    fn push_span(&mut self, span: StyledSpan) {
+        //merge with the previous span if it has the same style and is adjacent
+        if let Some(last) = self.spans.last_mut() {
+            if last.end == span.start && last.style == span.style {
+                last.end = span.end;
+                return;
+            }
+        }
+        self.spans.push(span);
+    }
+
+    ///Returns whether the text in this section has changed. The kill ring is
+    ///used by the kill and yank actions.
+    pub fn execute_input_action(&mut self, action: CursorAction, kill_ring: &mut KillRing) -> CursorActionResult {
+        let result = self.execute_input_action_priv(action, kill_ring);
         if result != CursorActionResult::Unchanged {
             self.generation += 1;
         }
+        debug_assert!(self.output_cursor <= self.input_cursor && self.input_cursor <= self.text.len());
+        debug_assert!(self.text.is_char_boundary(self.input_cursor));
         result
     }
 
-    fn execute_input_action_priv(&mut self, action: CursorAction) -> CursorActionResult {
+    fn execute_input_action_priv(&mut self, action: CursorAction, kill_ring: &mut KillRing) -> CursorActionResult {
         use self::CursorAction::*;
         use self::CursorActionResult::*;
+        let start = self.output_cursor;
+        let end = self.text.len();
+        let cursor = self.input_cursor;
+        let last_edit = mem::replace(&mut self.last_edit, LastEdit::Other);
+
         match action {
             Insert(ref text) => {
+                //typing is undone in one step up to the next whitespace
+                let is_typing = !text.contains(char::is_whitespace);
+                if !(is_typing && last_edit == LastEdit::Typing) {
+                    self.save_undo_state();
+                }
+                self.redo_stack.clear();
                 self.text.insert_str(self.input_cursor, text);
                 self.input_cursor = self.input_cursor + text.len();
                 if self.text.ends_with("\n") && self.input_cursor == self.text.len() {
-                    let input = self.text.split_off(self.output_cursor);
-                    self.input_cursor = self.output_cursor;
+                    //the submitted input stays in the transcript, marked as such
+                    let input = self.text[self.output_cursor..].to_string();
+                    let style = Style { user_input: true, ..Style::default() };
+                    let span = StyledSpan { start: self.output_cursor, end: self.text.len(), style: style };
+                    self.push_span(span);
+                    self.output_cursor = self.text.len();
+                    self.line_count += count_line_breaks(&input);
+                    self.seal_chunk_if_needed();
+                    //submitted input cannot be edited anymore
+                    self.undo_stack.clear();
                     LineCompleted(input)
                 } else {
+                    if is_typing {
+                        self.last_edit = LastEdit::Typing;
+                    }
                     Changed
                 }
             },
-            DeletePreviousChar | GotoPreviousChar => {
-                if self.input_cursor <= self.output_cursor { return Unchanged; }
-                //search for start of previous char
-                self.input_cursor -= 1;
-                while !self.text.is_char_boundary(self.input_cursor) {
-                    self.input_cursor -= 1;
-                }
-                if let DeletePreviousChar = action {
-                    self.text.remove(self.input_cursor);
-                }
-                Changed
+            DeletePreviousGrapheme => {
+                if cursor <= start { return Unchanged; }
+                let prev = prev_grapheme_boundary(&self.text, start, cursor);
+                self.edit(prev, cursor, "")
+            },
+            DeleteNextGrapheme => {
+                if cursor == end { return Unchanged; }
+                let next = next_grapheme_boundary(&self.text, start, cursor);
+                self.edit(cursor, next, "")
+            },
+            GotoPreviousGrapheme => {
+                if cursor <= start { return Unchanged; }
+                let prev = prev_grapheme_boundary(&self.text, start, cursor);
+                self.move_input_cursor(prev)
+            },
+            GotoNextGrapheme => {
+                if cursor == end { return Unchanged; }
+                let next = next_grapheme_boundary(&self.text, start, cursor);
+                self.move_input_cursor(next)
             },
-            DeleteNextChar => {
-                if self.input_cursor == self.text.len() { return Unchanged; }
-                self.text.remove(self.input_cursor); //cursor does not move
+            GotoStart => self.move_input_cursor(start),
+            GotoEnd => self.move_input_cursor(end),
+            GotoPreviousWord => {
+                let target = prev_word_start(&self.text, start, cursor, is_word_char);
+                self.move_input_cursor(target)
+            },
+            GotoNextWord => {
+                let target = next_word_end(&self.text, start, cursor, end, is_word_char);
+                self.move_input_cursor(target)
+            },
+            DeletePreviousWord => {
+                let from = prev_word_start(&self.text, start, cursor, is_non_whitespace);
+                self.kill(from, cursor, true, last_edit, kill_ring)
+            },
+            DeleteNextWord => {
+                let to = next_word_end(&self.text, start, cursor, end, is_word_char);
+                self.kill(cursor, to, false, last_edit, kill_ring)
+            },
+            KillToStart => self.kill(start, cursor, true, last_edit, kill_ring),
+            KillToEnd => self.kill(cursor, end, false, last_edit, kill_ring),
+            Yank => {
+                let text = match kill_ring.current() {
+                    Some(text) => text,
+                    None => return Unchanged,
+                };
+                let result = self.edit(cursor, cursor, &text);
+                self.last_edit = LastEdit::Yank(cursor - start, text.len());
+                result
+            },
+            YankPop => {
+                let (offset, len) = match last_edit {
+                    LastEdit::Yank(offset, len) => (offset, len),
+                    _ => return Unchanged,
+                };
+                let text = match kill_ring.rotate() {
+                    Some(text) => text,
+                    None => return Unchanged,
+                };
+                //replacing the previous yank is undone in one step with it
+                let result = self.replace_input(start + offset, start + offset + len, &text);
+                self.last_edit = LastEdit::Yank(offset, text.len());
+                result
+            },
+            TransposeGraphemes => {
+                //at the end of the input, the two characters before the cursor
+                //are swapped; otherwise the ones around the cursor
+                let pos = if cursor == end { prev_grapheme_boundary(&self.text, start, cursor) } else { cursor };
+                if pos <= start || pos >= end { return Unchanged; }
+                let before = prev_grapheme_boundary(&self.text, start, pos);
+                let after = next_grapheme_boundary(&self.text, start, pos);
+                let swapped = format!("{}{}", &self.text[pos..after], &self.text[before..pos]);
+                self.edit(before, after, &swapped)
+            },
+            Undo => {
+                let snapshot = match self.undo_stack.pop() {
+                    Some(snapshot) => snapshot,
+                    None => return Unchanged,
+                };
+                let current = self.snapshot();
+                self.redo_stack.push(current);
+                self.restore(snapshot);
                 Changed
             },
-            GotoNextChar => {
-                if self.input_cursor == self.text.len() { return Unchanged; }
-                self.input_cursor += 1;
-                while !self.text.is_char_boundary(self.input_cursor) {
-                    self.input_cursor += 1;
-                }
+            Redo => {
+                let snapshot = match self.redo_stack.pop() {
+                    Some(snapshot) => snapshot,
+                    None => return Unchanged,
+                };
+                let current = self.snapshot();
+                self.undo_stack.push(current);
+                self.restore(snapshot);
                 Changed
             },
         }
     }
+
+    fn move_input_cursor(&mut self, target: usize) -> CursorActionResult {
+        if target == self.input_cursor {
+            return CursorActionResult::Unchanged;
+        }
+        self.input_cursor = target;
+        CursorActionResult::Changed
+    }
+
+    ///Replaces the given range of the pending user input with `text` as a
+    ///new undo step, and puts the input cursor after the replacement.
+    fn edit(&mut self, from: usize, to: usize, text: &str) -> CursorActionResult {
+        if from == to && text.is_empty() {
+            return CursorActionResult::Unchanged;
+        }
+        self.save_undo_state();
+        self.redo_stack.clear();
+        self.replace_input(from, to, text)
+    }
+
+    ///Like edit(), but without recording an undo step.
+    fn replace_input(&mut self, from: usize, to: usize, text: &str) -> CursorActionResult {
+        debug_assert!(self.output_cursor <= from && from <= to && to <= self.text.len());
+        self.text.drain(from..to);
+        self.text.insert_str(from, text);
+        self.input_cursor = from + text.len();
+        CursorActionResult::Changed
+    }
+
+    ///Removes the given range of the pending user input into the kill ring.
+    ///Consecutive kills are collected in the same kill ring entry.
+    fn kill(&mut self, from: usize, to: usize, backwards: bool, last_edit: LastEdit, kill_ring: &mut KillRing) -> CursorActionResult {
+        if from == to {
+            return CursorActionResult::Unchanged;
+        }
+        let text = self.text[from..to].to_string();
+        if last_edit == LastEdit::Kill {
+            kill_ring.extend(&text, backwards);
+        } else {
+            kill_ring.push(text);
+        }
+        let result = self.edit(from, to, "");
+        self.last_edit = LastEdit::Kill;
+        result
+    }
+
+    fn snapshot(&self) -> InputSnapshot {
+        InputSnapshot {
+            input: self.text[self.output_cursor..].to_string(),
+            cursor: self.input_cursor - self.output_cursor,
+        }
+    }
+
+    fn save_undo_state(&mut self) {
+        let snapshot = self.snapshot();
+        self.undo_stack.push(snapshot);
+        if self.undo_stack.len() > UNDO_LIMIT {
+            self.undo_stack.remove(0);
+        }
+    }
+
+    fn restore(&mut self, snapshot: InputSnapshot) {
+        let start = self.output_cursor;
+        self.text.truncate(start);
+        self.text.push_str(&snapshot.input);
+        self.input_cursor = start + snapshot.cursor;
+    }
+}
+
+///Returns the start of the grapheme cluster before `pos`. Only the pending
+///user input (starting at `start`) is segmented, so that input does not merge
+///with the preceding output.
+fn prev_grapheme_boundary(text: &str, start: usize, pos: usize) -> usize {
+    let input = &text[start..];
+    let mut cursor = GraphemeCursor::new(pos - start, input.len(), true);
+    //since the whole input is given as one chunk, this cannot be incomplete
+    match cursor.prev_boundary(input, 0) {
+        Ok(Some(offset)) => start + offset,
+        _ => start,
+    }
+}
+
+///Returns the end of the grapheme cluster after `pos`, see
+///prev_grapheme_boundary().
+fn next_grapheme_boundary(text: &str, start: usize, pos: usize) -> usize {
+    let input = &text[start..];
+    let mut cursor = GraphemeCursor::new(pos - start, input.len(), true);
+    match cursor.next_boundary(input, 0) {
+        Ok(Some(offset)) => start + offset,
+        _ => text.len(),
+    }
+}
+
+///Chooses where the next chunk ends when sealing from the start of `text`
+///(which is at least CHUNK_SIZE bytes long): after the last line break within
+///the first CHUNK_SIZE bytes. When a single line is longer than that, it is
+///split across chunks (preferably after a space), since the cost of appending
+///output must not depend on the length of the current line. The view lays out
+///each chunk separately, so such a line shows a line break at each seam. This
+///is accepted since the line occupies dozens of rows anyway.
+fn find_seal_offset(text: &str) -> usize {
+    let window = &text.as_bytes()[0..CHUNK_SIZE];
+    if let Some(idx) = window.iter().rposition(|&b| b == b'\n') {
+        return idx + 1;
+    }
+    if let Some(idx) = window[CHUNK_SIZE/2..].iter().rposition(|&b| b == b' ') {
+        return CHUNK_SIZE/2 + idx + 1;
+    }
+    let mut offset = CHUNK_SIZE;
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+///Classifies a grapheme cluster by its base character.
+fn is_word_char(grapheme: &str) -> bool {
+    grapheme.chars().next().map_or(false, char::is_alphanumeric)
+}
+
+fn is_non_whitespace(grapheme: &str) -> bool {
+    grapheme.chars().next().map_or(false, |ch| !ch.is_whitespace())
+}
+
+///Returns the start of the word before `pos` (skipping non-word grapheme
+///clusters directly before `pos`), but not before `min`.
+fn prev_word_start<F: Fn(&str) -> bool>(text: &str, min: usize, pos: usize, is_word: F) -> usize {
+    let mut result = pos;
+    let mut in_word = false;
+    for (idx, grapheme) in text[min..pos].grapheme_indices(true).rev() {
+        if is_word(grapheme) {
+            in_word = true;
+        } else if in_word {
+            break;
+        }
+        result = min + idx;
+    }
+    result
+}
+
+///Returns the end of the word after `pos` (skipping non-word grapheme clusters
+///directly after `pos`), but not after `max`. The text is segmented from `min`
+///onwards, since clusters may depend on what precedes them.
+fn next_word_end<F: Fn(&str) -> bool>(text: &str, min: usize, pos: usize, max: usize, is_word: F) -> usize {
+    let mut result = pos;
+    let mut in_word = false;
+    let graphemes = text[min..max].grapheme_indices(true).skip_while(|&(idx, _)| min + idx < pos);
+    for (idx, grapheme) in graphemes {
+        if is_word(grapheme) {
+            in_word = true;
+        } else if in_word {
+            break;
+        }
+        result = min + idx + grapheme.len();
+    }
+    result
+}
+
+fn count_line_breaks(text: &str) -> usize {
+    text.bytes().filter(|&b| b == b'\n').count()
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+///Parser state for the output of a client program. This needs to live as long
+///as the stream that it parses (i.e. one per connection), so that escape
+///sequences and multi-byte characters can be split across multiple reads.
+pub struct OutputParser {
+    parser: vte::Parser,
+    performer: ANSIPerformer,
+}
+
+impl OutputParser {
+    pub fn new() -> OutputParser {
+        OutputParser {
+            parser: vte::Parser::new(),
+            performer: ANSIPerformer::new(),
+        }
+    }
+
+    ///Feeds the given bytes into the parser, and returns the text that was
+    ///decoded from it, along with styled spans (relative to the returned text).
+    ///Incomplete sequences at the end of `input` are kept in the parser state.
+    ///
+    ///While the client uses the alternate screen, output goes into `screen`
+    ///instead of the returned text. The parser creates and removes the screen
+    ///when the client enters or leaves the alternate screen; new screens have
+    ///the given `screen_size` (columns, rows).
+    pub fn parse(&mut self, input: &[u8], output_protected: bool, screen: &mut Option<GridSection>, screen_size: (usize, usize)) -> (String, Vec<StyledSpan>) {
+        self.performer.screen = screen.take();
+        self.performer.screen_size = screen_size;
+        self.performer.set_protected(output_protected);
+        for &byte in input {
+            //In protected mode, control characters are shown literally instead of
+            //being interpreted. Only line breaks, tabs and printable characters go
+            //into the parser, so that it can never enter an escape sequence.
+            if output_protected && is_protected_control(byte) {
+                self.performer.print_caret(byte);
+            } else {
+                self.parser.advance(&mut self.performer, byte);
+            }
+        }
+        *screen = self.performer.screen.take();
+        self.performer.take_output()
+    }
+}
+
+///Returns whether the given byte is a C0 control character (or DEL) that needs to
+///be shown in caret notation when output is protected.
+fn is_protected_control(byte: u8) -> bool {
+    match byte {
+        b'\n' | b'\r' | b'\t' => false,
+        0x00...0x1F | 0x7F => true,
+        _ => false,
+    }
+}
+
 struct ANSIPerformer {
     //externally accessible state
     string: String,
-    //configuration (set only at construction time)
+    ///Styled ranges of `self.string` (indexes are relative to `self.string`).
+    spans: Vec<StyledSpan>,
+    ///The style selected by the most recent SGR escape sequence.
+    style: Style,
+    //configuration (set before each call to OutputParser::parse)
     protected: bool,
+    ///The alternate screen, if active. This is owned by the model::Document and
+    ///only lent to the parser during OutputParser::parse.
+    screen: Option<GridSection>,
+    screen_size: (usize, usize),
     //internal state (manipulated by the vte::Perform trait method implementations)
     ignore_next_nl: bool,
+    ///Start index (into `self.string`) of the text printed with `self.style`.
+    span_start: usize,
 }
 
 impl ANSIPerformer {
-    fn new(output_protected: bool) -> Self {
+    fn new() -> Self {
         Self {
             string: String::new(),
-            protected: output_protected,
+            spans: Vec::new(),
+            style: Style::default(),
+            protected: false,
+            screen: None,
+            screen_size: (80, 24),
             ignore_next_nl: false,
+            span_start: 0,
+        }
+    }
+
+    ///Records the text printed since the last style change as a span (if it is
+    ///styled at all).
+    fn finish_span(&mut self) {
+        let end = self.string.len();
+        if end > self.span_start && !self.style.is_default() {
+            self.spans.push(StyledSpan { start: self.span_start, end: end, style: self.style });
         }
+        self.span_start = end;
+    }
+
+    ///Returns the text and spans collected so far, and resets the collection
+    ///buffers. The current style and parser state are retained.
+    fn take_output(&mut self) -> (String, Vec<StyledSpan>) {
+        self.finish_span();
+        self.span_start = 0;
+        (mem::replace(&mut self.string, String::new()), mem::replace(&mut self.spans, Vec::new()))
+    }
+
+    fn set_protected(&mut self, protected: bool) {
+        self.protected = protected;
+        let mut style = self.style;
+        style.protected = protected;
+        self.set_style(style);
+    }
+
+    ///Shows a control character in caret notation, e.g. "^[" for ESC.
+    fn print_caret(&mut self, byte: u8) {
+        if let Some(ref mut grid) = self.screen {
+            grid.print('^', self.style);
+            grid.print((byte ^ 0x40) as char, self.style);
+            return;
+        }
+        self.string.push('^');
+        self.string.push((byte ^ 0x40) as char);
+        self.ignore_next_nl = false;
+    }
+
+    fn set_style(&mut self, style: Style) {
+        if style != self.style {
+            self.finish_span();
+            self.style = style;
+        }
+    }
+
+    ///Handles `CSI ? ... h` (when `value` is true) and `CSI ? ... l` (when
+    ///`value` is false).
+    fn set_private_modes(&mut self, params: &[i64], value: bool) {
+        for &param in params {
+            match param {
+                //alternate screen (1049 also saves/restores the cursor, which
+                //only matters for the grid that is discarded anyway)
+                47 | 1047 | 1049 => {
+                    if value && self.screen.is_none() {
+                        let (width, height) = self.screen_size;
+                        self.screen = Some(GridSection::new(width, height));
+                    } else if !value {
+                        self.screen = None;
+                    }
+                },
+                _ => match self.screen {
+                    Some(ref mut grid) => match param {
+                        6 => grid.set_origin_mode(value),
+                        7 => grid.set_auto_wrap(value),
+                        25 => grid.set_cursor_visible(value),
+                        _ => info!("unsupported DEC private mode: {} = {}", param, value),
+                    },
+                    None => info!("unsupported DEC private mode outside of alternate screen: {} = {}", param, value),
+                },
+            }
+        }
+    }
+
+    ///Handles CSI sequences that only make sense on the alternate screen.
+    ///Returns false if the sequence is not supported.
+    fn grid_csi_dispatch(&mut self, params: &[i64], byte: char) -> bool {
+        let style = self.style;
+        let mut restored_style = None;
+        {
+            let grid = match self.screen {
+                Some(ref mut grid) => grid,
+                None => return false,
+            };
+            let n = count_param(params, 0);
+            match byte {
+                'A' => grid.move_vertically(-(n as isize)),
+                'B' | 'e' => grid.move_vertically(n as isize),
+                'C' | 'a' => grid.move_horizontally(n as isize),
+                'D' => grid.move_horizontally(-(n as isize)),
+                'E' => { grid.move_vertically(n as isize); grid.carriage_return() },
+                'F' => { grid.move_vertically(-(n as isize)); grid.carriage_return() },
+                'G' | '`' => grid.goto_col(n - 1),
+                'd' => grid.goto_row(n - 1),
+                'H' | 'f' => grid.goto(n - 1, count_param(params, 1) - 1),
+                'J' => grid.erase_in_display(params.get(0).cloned().unwrap_or(0), style),
+                'K' => grid.erase_in_line(params.get(0).cloned().unwrap_or(0), style),
+                'X' => grid.erase_chars(n, style),
+                '@' => grid.insert_chars(n, style),
+                'P' => grid.delete_chars(n, style),
+                'L' => grid.insert_lines(n),
+                'M' => grid.delete_lines(n),
+                'S' => grid.scroll_up(n),
+                'T' => grid.scroll_down(n),
+                'r' => {
+                    let height = grid.height();
+                    let top = count_param(params, 0);
+                    let bottom = match params.get(1) {
+                        Some(&b) if b > 0 => b as usize,
+                        _ => height,
+                    };
+                    grid.set_scroll_region(top - 1, bottom - 1);
+                },
+                's' => grid.save_cursor(style),
+                'u' => restored_style = grid.restore_cursor(),
+                _ => return false,
+            }
+        }
+        if let Some(style) = restored_style {
+            self.set_style(style);
+        }
+        true
+    }
+}
+
+///Returns the numeric parameter at the given index of an escape sequence,
+///where 0 or a missing parameter means 1 (as is the case for counts and
+///1-based coordinates).
+fn count_param(params: &[i64], idx: usize) -> usize {
+    match params.get(idx) {
+        Some(&n) if n > 0 => n as usize,
+        _ => 1,
     }
 }
 
 impl vte::Perform for ANSIPerformer {
     fn print(&mut self, ch: char) {
+        if let Some(ref mut grid) = self.screen {
+            grid.print(ch, self.style);
+            return;
+        }
         self.string.push(ch);
 
         //received a character that is not NL
@@ -191,6 +1000,18 @@ impl vte::Perform for ANSIPerformer {
     }
 
     fn execute(&mut self, byte: u8) {
+        if let Some(ref mut grid) = self.screen {
+            //the alternate screen has real cursor movement, so no conversion here
+            match byte {
+                b'\r' => grid.carriage_return(),
+                b'\n' | 0x0B | 0x0C => grid.linefeed(),
+                0x08 => grid.backspace(),
+                b'\t' => grid.tab(),
+                0x07 => {}, //BEL
+                _ => info!("STUB: ANSIPerformer::execute({:?})", byte),
+            }
+            return;
+        }
         match byte {
             //NOTE: CR-NL, CR -> NL conversion according to [vt6/term1.0, sect. 1.2]
             b'\r' => {
@@ -206,6 +1027,10 @@ impl vte::Perform for ANSIPerformer {
                     self.string.push('\n');
                 }
             },
+            b'\t' => {
+                self.string.push('\t');
+                self.ignore_next_nl = false;
+            },
             _ => info!("STUB: ANSIPerformer::execute({:?})", byte),
         };
     }
@@ -242,13 +1067,575 @@ impl vte::Perform for ANSIPerformer {
         if self.protected {
             return;
         }
-        info!("stub: ANSIPerformer::csi_dispatch({:?}, {:?}, {:?}, {:?})", params, intermediates, ignore, byte);
+        match byte {
+            //SGR (Select Graphic Rendition)
+            'm' if intermediates.is_empty() => {
+                let mut style = self.style;
+                style.apply_sgr(params);
+                self.set_style(style);
+            },
+            //DECSET, DECRST
+            'h' if intermediates == b"?" => self.set_private_modes(params, true),
+            'l' if intermediates == b"?" => self.set_private_modes(params, false),
+            _ => {
+                if !(intermediates.is_empty() && self.grid_csi_dispatch(params, byte)) {
+                    info!("stub: ANSIPerformer::csi_dispatch({:?}, {:?}, {:?}, {:?})", params, intermediates, ignore, byte);
+                }
+            },
+        }
     }
 
     fn esc_dispatch(&mut self, params: &[i64], intermediates: &[u8], ignore: bool, byte: u8) {
         if self.protected {
             return;
         }
+        if intermediates.is_empty() && self.screen.is_some() {
+            let style = self.style;
+            let mut restored_style = None;
+            {
+                let grid = self.screen.as_mut().unwrap();
+                match byte {
+                    b'7' => grid.save_cursor(style),
+                    b'8' => restored_style = grid.restore_cursor(),
+                    b'D' => grid.linefeed(),
+                    b'E' => { grid.carriage_return(); grid.linefeed() },
+                    b'M' => grid.reverse_index(),
+                    _ => {
+                        info!("stub: ANSIPerformer::esc_dispatch({:?}, {:?}, {:?}, {:?})", params, intermediates, ignore, byte);
+                        return;
+                    },
+                }
+            }
+            if let Some(style) = restored_style {
+                self.set_style(style);
+            }
+            return;
+        }
         info!("stub: ANSIPerformer::esc_dispatch({:?}, {:?}, {:?}, {:?})", params, intermediates, ignore, byte);
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use model::{Color, Style, StyledSpan};
+
+    ///Feeds the given pieces into one parser (like consecutive reads from a
+    ///connection), and collects the output in a section. Returns the section's
+    ///text and spans.
+    fn parse_pieces(pieces: &[&[u8]], protected: bool) -> (String, Vec<StyledSpan>) {
+        let mut parser = OutputParser::new();
+        let mut screen = None;
+        let mut section = Section::new(SectionID::new());
+        for piece in pieces {
+            let (text, spans) = parser.parse(piece, protected, &mut screen, (80, 24));
+            section.append_output(&text, spans);
+        }
+        assert!(screen.is_none());
+        (section.text().to_string(), section.spans().to_vec())
+    }
+
+    ///Checks that splitting the input at any point (and into single bytes)
+    ///gives the same result as parsing it in one piece. Returns that result.
+    fn check_all_splits(input: &[u8], protected: bool) -> (String, Vec<StyledSpan>) {
+        let expected = parse_pieces(&[input], protected);
+        for idx in 0..(input.len() + 1) {
+            let (head, tail) = input.split_at(idx);
+            assert_eq!(parse_pieces(&[head, tail], protected), expected, "split at byte {}", idx);
+        }
+        let bytes: Vec<&[u8]> = input.chunks(1).collect();
+        assert_eq!(parse_pieces(&bytes, protected), expected, "split into single bytes");
+        expected
+    }
+
+    fn span(start: usize, end: usize, style: Style) -> StyledSpan {
+        StyledSpan { start, end, style }
+    }
+
+    #[test]
+    fn split_sgr_sequences() {
+        let (text, spans) = check_all_splits(b"plain \x1b[1;31mbold red\x1b[0m \x1b[38;2;10;20;30mrgb\x1b[m end\n", false);
+        assert_eq!(text, "plain bold red rgb end\n");
+        let bold_red = Style { bold: true, foreground: Some(Color::Palette(1)), ..Style::default() };
+        let rgb = Style { foreground: Some(Color::RGB(10, 20, 30)), ..Style::default() };
+        assert_eq!(spans, vec![span(6, 14, bold_red), span(15, 18, rgb)]);
+    }
+
+    #[test]
+    fn split_utf8() {
+        let input = "Grüße, 世界 \x1b[4m🎉\x1b[24m!\n";
+        let (text, spans) = check_all_splits(input.as_bytes(), false);
+        assert_eq!(text, "Grüße, 世界 🎉!\n");
+        let underline = Style { underline: true, ..Style::default() };
+        let start = "Grüße, 世界 ".len();
+        assert_eq!(spans, vec![span(start, start + 4, underline)]);
+    }
+
+    #[test]
+    fn split_line_breaks() {
+        let (text, spans) = check_all_splits(b"line 1\r\nline 2\r\rline 4\n\r\nline 6\n\n", false);
+        assert_eq!(text, "line 1\nline 2\n\nline 4\n\nline 6\n\n");
+        assert_eq!(spans, vec![]);
+    }
+
+    #[test]
+    fn split_protected_output() {
+        let (text, spans) = check_all_splits(b"\x1b[31mnot red\x1b[0m\x07\r\ntab\there\n", true);
+        assert_eq!(text, "^[[31mnot red^[[0m^G\ntab\there\n");
+        let protected = Style { protected: true, ..Style::default() };
+        assert_eq!(spans, vec![span(0, text.len(), protected)]);
+    }
+
+    ///Returns the pending user input of the section, with `|` marking the
+    ///input cursor. Also checks the invariants of the cursors.
+    fn input_state(section: &Section) -> String {
+        let text = section.text();
+        let (output_cursor, input_cursor) = (section.output_cursor(), section.input_cursor());
+        assert!(output_cursor <= input_cursor && input_cursor <= text.len(),
+            "output cursor {}, input cursor {}, length {}", output_cursor, input_cursor, text.len());
+        assert!(text.is_char_boundary(output_cursor) && text.is_char_boundary(input_cursor));
+        format!("{}|{}", &text[output_cursor..input_cursor], &text[input_cursor..])
+    }
+
+    ///Executes the given editing actions in order. After each one, the
+    ///pending input must match the corresponding expected state.
+    fn check_actions(section: &mut Section, kill_ring: &mut KillRing, steps: Vec<(CursorAction, &str)>) {
+        for (idx, (action, expected)) in steps.into_iter().enumerate() {
+            section.execute_input_action(action, kill_ring);
+            assert_eq!(input_state(section), expected, "after step {}", idx);
+        }
+    }
+
+    fn output(section: &Section) -> &str {
+        &section.text()[..section.output_cursor()]
+    }
+
+    #[test]
+    fn kill_yank_and_undo() {
+        use super::CursorAction::*;
+        let mut section = Section::new(SectionID::new());
+        let mut kill_ring = KillRing::new();
+        check_actions(&mut section, &mut kill_ring, vec![
+            (Insert("echo hello world".into()), "echo hello world|"),
+            (DeletePreviousWord, "echo hello |"),
+            //consecutive kills go into the same kill ring entry
+            (DeletePreviousWord, "echo |"),
+            (GotoStart, "|echo "),
+            (Yank, "hello world|echo "),
+            (KillToEnd, "hello world|"),
+            (Yank, "hello worldecho |"),
+            (YankPop, "hello worldhello world|"),
+            (YankPop, "hello worldecho |"),
+            //the yank and the following yank-pops are undone in one step
+            (Undo, "hello world|"),
+            (Undo, "hello world|echo "),
+            (Redo, "hello world|"),
+            (Redo, "hello worldecho |"),
+            (Redo, "hello worldecho |"),
+            (KillToStart, "|"),
+            (Yank, "hello worldecho |"),
+            (GotoStart, "|hello worldecho "),
+            (DeleteNextWord, "| worldecho "),
+            (DeleteNextWord, "| "),
+            (Yank, "hello worldecho| "),
+        ]);
+    }
+
+    #[test]
+    fn yank_pop_requires_yank() {
+        use super::CursorAction::*;
+        let mut section = Section::new(SectionID::new());
+        let mut kill_ring = KillRing::new();
+        check_actions(&mut section, &mut kill_ring, vec![
+            (Yank, "|"),
+            (Insert("one two".into()), "one two|"),
+            (DeletePreviousWord, "one |"),
+            (YankPop, "one |"),
+            (Yank, "one two|"),
+            (GotoPreviousGrapheme, "one tw|o"),
+            (YankPop, "one tw|o"),
+        ]);
+    }
+
+    #[test]
+    fn transpose() {
+        use super::CursorAction::*;
+        let mut section = Section::new(SectionID::new());
+        let mut kill_ring = KillRing::new();
+        check_actions(&mut section, &mut kill_ring, vec![
+            //at the end of the input, the last two characters are swapped
+            (Insert("abc".into()), "abc|"),
+            (TransposeGraphemes, "acb|"),
+            (GotoStart, "|acb"),
+            (TransposeGraphemes, "|acb"),
+            //otherwise, the characters around the cursor
+            (GotoNextGrapheme, "a|cb"),
+            (TransposeGraphemes, "ca|b"),
+            (Undo, "a|cb"),
+            (Undo, "abc|"),
+            (Undo, "|"),
+            (Undo, "|"),
+            (Redo, "abc|"),
+        ]);
+    }
+
+    #[test]
+    fn edit_while_output_arrives() {
+        use super::CursorAction::*;
+        let mut section = Section::new(SectionID::new());
+        let mut kill_ring = KillRing::new();
+        section.append_output("$ ", Vec::new());
+        check_actions(&mut section, &mut kill_ring, vec![
+            (Insert("ls -l".into()), "ls -l|"),
+            (GotoPreviousWord, "ls -|l"),
+        ]);
+
+        //output goes before the pending input and does not move the input cursor
+        //relative to the input
+        section.append_output("note\n", Vec::new());
+        assert_eq!(output(&section), "$ note\n");
+        check_actions(&mut section, &mut kill_ring, vec![
+            (KillToStart, "|l"),
+            (GotoEnd, "l|"),
+        ]);
+
+        section.append_output("$ ", Vec::new());
+        assert_eq!(output(&section), "$ note\n$ ");
+        check_actions(&mut section, &mut kill_ring, vec![
+            (Yank, "lls -|"),
+            (TransposeGraphemes, "lls- |"),
+        ]);
+
+        section.append_output("more output\n", Vec::new());
+        check_actions(&mut section, &mut kill_ring, vec![
+            (Undo, "lls -|"),
+            (Undo, "l|"),
+            (Undo, "ls -|l"),
+            (Undo, "|"),
+            (Redo, "ls -|l"),
+            (KillToEnd, "ls -|"),
+        ]);
+        assert_eq!(output(&section), "$ note\n$ more output\n");
+
+        //undo never reaches into output
+        check_actions(&mut section, &mut kill_ring, vec![
+            (Undo, "ls -|l"),
+            (Undo, "|"),
+            (Undo, "|"),
+        ]);
+        assert_eq!(output(&section), "$ note\n$ more output\n");
+
+        //sealing output into chunks does not affect the pending input
+        section.append_output(&"output line\n".repeat(1000), Vec::new());
+        assert!(!section.chunks().is_empty());
+        check_actions(&mut section, &mut kill_ring, vec![
+            (Redo, "ls -|l"),
+            (Redo, "ls -|"),
+            (GotoStart, "|ls -"),
+            (Yank, "l|ls -"),
+        ]);
+    }
+
+    #[test]
+    fn truncate_front() {
+        use super::CursorAction::*;
+        let mut section = Section::new(SectionID::new());
+        let mut kill_ring = KillRing::new();
+        let bold = Style { bold: true, ..Style::default() };
+        section.append_output("ab\ncd\nef", vec![span(1, 4, bold)]);
+        section.execute_input_action(Insert("x".into()), &mut kill_ring);
+
+        //a span that starts in the removed text is cut off
+        assert_eq!(section.truncate_front_lines(1), 3);
+        assert_eq!((section.text(), section.spans()), ("cd\nefx", &[span(0, 1, bold)][..]));
+        assert_eq!(section.line_count(), 1);
+
+        //the cut is extended to the next line boundary
+        assert_eq!(section.truncate_front(1), 3);
+        assert_eq!((section.text(), section.spans()), ("efx", &[][..]));
+        assert_eq!(section.line_count(), 0);
+
+        //pending input is never removed
+        assert_eq!(section.truncate_front(10), 2);
+        assert_eq!(section.truncate_front_lines(1), 0);
+        assert_eq!(input_state(&section), "x|");
+        assert_eq!(section.output_cursor(), 0);
+
+        //sealed chunks are only removed as a whole
+        let mut section = Section::new(SectionID::new());
+        for idx in 0..500 {
+            section.append_output(&format!("line {:04}\n", idx), Vec::new());
+        }
+        let first_chunk_len = section.chunks()[0].text().len();
+        let first_chunk_lines = section.chunks()[0].line_count;
+        assert_eq!(section.truncate_front(1), first_chunk_len);
+        assert_eq!(section.line_count(), 500 - first_chunk_lines);
+        assert_eq!(section.len(), 5000 - first_chunk_len);
+        assert!(section.chunks().is_empty());
+        assert!(section.text().starts_with(&format!("line {:04}\n", first_chunk_lines)));
+    }
+
+    ///Parses the test cases in GraphemeBreakTest.txt into the expected grapheme
+    ///clusters.
+    fn grapheme_break_tests() -> Vec<Vec<String>> {
+        include_str!("testdata/GraphemeBreakTest.txt").lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let mut clusters = Vec::new();
+                let mut current = String::new();
+                for field in line.split_whitespace() {
+                    match field {
+                        "÷" => if !current.is_empty() {
+                            clusters.push(mem::replace(&mut current, String::new()));
+                        },
+                        "×" => {},
+                        hex => {
+                            let code = u32::from_str_radix(hex, 16).unwrap();
+                            current.push(::std::char::from_u32(code).unwrap());
+                        },
+                    }
+                }
+                clusters
+            })
+            .collect()
+    }
+
+    ///Checks that the grapheme motions step over the given clusters one at a
+    ///time when they are entered as pending input.
+    fn check_grapheme_motions(clusters: &[String]) {
+        use super::CursorAction::*;
+        use super::CursorActionResult::*;
+        let input = clusters.concat();
+        let prefix = |n: usize| clusters[..n].concat();
+        let suffix = |n: usize| clusters[n..].concat();
+
+        let mut section = Section::new(SectionID::new());
+        let mut kill_ring = KillRing::new();
+        section.append_output("$ ", Vec::new());
+        //the trailing control character stops input ending in "\n" from being
+        //submitted; since there is always a break before it (GB5), it does not
+        //change the clusters before it
+        section.execute_input_action(Insert("\u{1}".into()), &mut kill_ring);
+        section.execute_input_action(GotoStart, &mut kill_ring);
+        section.execute_input_action(Insert(input.clone()), &mut kill_ring);
+
+        for n in (0..clusters.len()).rev() {
+            assert!(section.execute_input_action(GotoPreviousGrapheme, &mut kill_ring) == Changed);
+            assert_eq!(input_state(&section), format!("{}|{}\u{1}", prefix(n), suffix(n)), "{:?}", clusters);
+        }
+        assert!(section.execute_input_action(GotoPreviousGrapheme, &mut kill_ring) == Unchanged);
+
+        for n in 1..(clusters.len() + 1) {
+            assert!(section.execute_input_action(GotoNextGrapheme, &mut kill_ring) == Changed);
+            assert_eq!(input_state(&section), format!("{}|{}\u{1}", prefix(n), suffix(n)), "{:?}", clusters);
+        }
+
+        for n in (0..clusters.len()).rev() {
+            assert!(section.execute_input_action(DeletePreviousGrapheme, &mut kill_ring) == Changed);
+            assert_eq!(input_state(&section), format!("{}|\u{1}", prefix(n)), "{:?}", clusters);
+        }
+        assert!(section.execute_input_action(DeletePreviousGrapheme, &mut kill_ring) == Unchanged);
+
+        section.execute_input_action(Insert(input), &mut kill_ring);
+        section.execute_input_action(GotoStart, &mut kill_ring);
+        for n in 1..(clusters.len() + 1) {
+            assert!(section.execute_input_action(DeleteNextGrapheme, &mut kill_ring) == Changed);
+            assert_eq!(input_state(&section), format!("|{}\u{1}", suffix(n)), "{:?}", clusters);
+        }
+        assert_eq!(output(&section), "$ ");
+    }
+
+    #[test]
+    fn grapheme_break_test() {
+        let tests = grapheme_break_tests();
+        assert_eq!(tests.len(), 744);
+        for clusters in tests {
+            check_grapheme_motions(&clusters);
+        }
+    }
+
+    #[test]
+    fn grapheme_motions() {
+        let tests: &[&[&str]] = &[
+            //skin tone modifiers
+            &["\u{1F44D}\u{1F3FD}", "\u{261D}\u{1F3FB}", "\u{1F3FF}"],
+            //ZWJ sequences
+            &["\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466}", "\u{1F469}\u{200D}\u{2764}\u{FE0F}\u{200D}\u{1F468}"],
+            //regional indicators pair up into flags
+            &["\u{1F1E9}\u{1F1EA}", "\u{1F1EB}\u{1F1F7}", "\u{1F1EC}"],
+            //decomposed accents
+            &["e\u{301}", "a\u{308}\u{304}", "c\u{327}", "o"],
+            //Hangul syllables from conjoining jamo
+            &["\u{1100}\u{1161}\u{11A8}", "\u{1100}\u{1161}"],
+            //CR LF is one cluster
+            &["a", "\r\n", "b", "\n"],
+        ];
+        for test in tests {
+            let clusters: Vec<String> = test.iter().map(|s| s.to_string()).collect();
+            check_grapheme_motions(&clusters);
+        }
+    }
+
+    #[test]
+    fn input_does_not_merge_with_output() {
+        use super::CursorAction::*;
+        use super::CursorActionResult::*;
+        //in one string, each output would form a grapheme cluster with the
+        //start of the input following it
+        let tests = [
+            ("e", "\u{301}"),
+            ("\u{1F44D}", "\u{1F3FD}"),
+            ("\u{1F469}\u{200D}", "\u{1F467}"),
+            ("\u{1F1E9}", "\u{1F1EA}\u{1F1F8}"),
+            ("\u{600}", "x"),
+        ];
+        for &(output_text, input) in &tests {
+            let mut section = Section::new(SectionID::new());
+            let mut kill_ring = KillRing::new();
+            section.append_output(output_text, Vec::new());
+            check_actions(&mut section, &mut kill_ring, vec![
+                (Insert(input.into()), &format!("{}|", input)),
+                (GotoPreviousGrapheme, &format!("|{}", input)),
+                (GotoNextGrapheme, &format!("{}|", input)),
+                (DeletePreviousGrapheme, "|"),
+                (Insert(input.into()), &format!("{}|", input)),
+                (GotoStart, &format!("|{}", input)),
+                (DeleteNextGrapheme, "|"),
+            ]);
+            assert!(section.execute_input_action(GotoPreviousGrapheme, &mut kill_ring) == Unchanged);
+            assert!(section.execute_input_action(DeletePreviousGrapheme, &mut kill_ring) == Unchanged);
+            assert_eq!(output(&section), output_text);
+        }
+    }
+
+    ///The storage of a section before it was split into chunks: a single
+    ///string that the view laid out again as a whole after each change. This
+    ///is the baseline for append_throughput().
+    struct FlatSection {
+        text: String,
+        output_cursor: usize,
+        line_count: usize,
+    }
+
+    impl FlatSection {
+        fn append_output(&mut self, text: &str) {
+            self.text.insert_str(self.output_cursor, text);
+            self.output_cursor += text.len();
+            self.line_count += count_line_breaks(text);
+        }
+    }
+
+    ///Stands in for the work of laying out the given text.
+    fn lay_out(text: &str) -> usize {
+        text.chars().count()
+    }
+
+    ///Appends `total` bytes of output in reads of the given size, once with
+    ///FlatSection and once with Section. The work of the view is approximated
+    ///by lay_out() for each text that it lays out after each read: the whole
+    ///text for FlatSection, but only new chunks and the open part for Section.
+    ///Returns the time taken in seconds for both.
+    fn time_appends(read: &[u8], total: usize) -> (f64, f64) {
+        let seconds = |start: Instant| {
+            let elapsed = start.elapsed();
+            elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9
+        };
+        let reads = total / read.len();
+        let mut laid_out = 0;
+
+        let mut parser = OutputParser::new();
+        let mut screen = None;
+        let mut flat = FlatSection { text: String::new(), output_cursor: 0, line_count: 0 };
+        let start = Instant::now();
+        for _ in 0..reads {
+            let (text, _) = parser.parse(read, false, &mut screen, (80, 24));
+            flat.append_output(&text);
+            laid_out += lay_out(&flat.text);
+        }
+        let flat_seconds = seconds(start);
+        assert_eq!(flat.text.len(), total);
+
+        let mut parser = OutputParser::new();
+        let mut section = Section::new(SectionID::new());
+        let mut next_chunk_id = 0;
+        let start = Instant::now();
+        for _ in 0..reads {
+            let (text, spans) = parser.parse(read, false, &mut screen, (80, 24));
+            section.append_output(&text, spans);
+            for chunk in section.chunks().iter() {
+                if chunk.id() >= next_chunk_id {
+                    laid_out += lay_out(chunk.text());
+                    next_chunk_id = chunk.id() + 1;
+                }
+            }
+            laid_out += lay_out(section.text());
+        }
+        let chunked_seconds = seconds(start);
+        assert_eq!(section.len(), total);
+        assert_eq!(section.line_count(), flat.line_count);
+        //the open part must stay small, otherwise appending is quadratic
+        assert!(section.text().len() < CHUNK_SIZE);
+        assert!(section.chunks().iter().all(|c| c.text().len() <= CHUNK_SIZE));
+
+        assert!(laid_out > 0);
+        (flat_seconds, chunked_seconds)
+    }
+
+    ///Compares how fast output can be appended and laid out with and without
+    ///chunks, both for regular lines and for a single long line. Run with
+    ///`cargo test --release -- --ignored --nocapture append_throughput`.
+    #[test]
+    #[ignore]
+    fn append_throughput() {
+        let total: usize = 16 << 20;
+        let line = b"0123456789abcdef 0123456789abcdef 0123456789abcdef 0123456789abcdef\n";
+        let with_lines: Vec<u8> = line.iter().cycle().take(4096).cloned().collect();
+        let single_line = vec![b'x'; 4096];
+
+        for &(name, ref read) in [("with line breaks", with_lines), ("single line", single_line)].iter() {
+            let (flat, chunked) = time_appends(read, total);
+            let mib = (total >> 20) as f64;
+            println!("{}: {} MiB in {:.2}s ({:.1} MiB/s) before, {:.2}s ({:.1} MiB/s) with chunks",
+                name, total >> 20, flat, mib / flat, chunked, mib / chunked);
+            assert!(chunked * 10. < flat, "no significant speedup");
+        }
+    }
+
+    ///Appending a large amount of output at once must not produce huge chunks.
+    #[test]
+    fn seal_large_appends() {
+        let bold = Style { bold: true, ..Style::default() };
+        let lines: String = (0..2000).map(|idx| format!("line {:04}\n", idx)).collect();
+        let long_line = "word ".repeat(2000);
+        let unbroken = "ä".repeat(5000);
+
+        for text in &[lines, long_line, unbroken] {
+            let mut section = Section::new(SectionID::new());
+            section.append_output(text, vec![span(1, text.len() - 1, bold)]);
+
+            assert!(section.chunks().len() >= 2);
+            assert!(section.text().len() < CHUNK_SIZE);
+            let mut full_text = String::new();
+            let mut full_spans = Vec::new();
+            for chunk in section.chunks().iter() {
+                assert!(chunk.text().len() <= CHUNK_SIZE);
+                let offset = full_text.len();
+                full_spans.extend(chunk.spans().iter().map(|s| span(s.start + offset, s.end + offset, s.style)));
+                full_text.push_str(chunk.text());
+            }
+            let offset = full_text.len();
+            full_spans.extend(section.spans().iter().map(|s| span(s.start + offset, s.end + offset, s.style)));
+            full_text.push_str(section.text());
+
+            assert_eq!(&full_text, text);
+            //the span is split at each seam, but covers the same text
+            assert_eq!(full_spans.len(), section.chunks().len() + 1);
+            assert!(full_spans.windows(2).all(|w| w[0].end == w[1].start));
+            assert_eq!((full_spans[0].start, full_spans.last().unwrap().end), (1, text.len() - 1));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+///A color as selected by an SGR escape sequence. The model does not know about
+///actual RGB values for palette colors; the view resolves them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Color {
+    ///Index into the 256-color palette. Indexes 0..16 are the classic ANSI colors
+    ///(normal and bright), 16..232 is the 6x6x6 color cube, and 232..256 is the
+    ///grayscale ramp.
+    Palette(u8),
+    ///A truecolor value as set by `CSI 38;2;R;G;B m` or `CSI 48;2;R;G;B m`.
+    RGB(u8, u8, u8),
+}
+
+///The set of text attributes that can be set by SGR escape sequences.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Style {
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub reverse: bool,
+    ///Set on output that was received while the term.output-protected property
+    ///was enabled. This cannot be changed by SGR escape sequences.
+    pub protected: bool,
+    ///Set on user input that was submitted to the client. This cannot be
+    ///changed by SGR escape sequences.
+    pub user_input: bool,
+}
+
+impl Style {
+    pub fn is_default(&self) -> bool {
+        *self == Style::default()
+    }
+
+    ///Resets all attributes that can be set by SGR escape sequences.
+    fn reset(&mut self) {
+        *self = Style { protected: self.protected, user_input: self.user_input, ..Style::default() };
+    }
+
+    ///Applies the parameters of an SGR escape sequence (`CSI ... m`) to this
+    ///style.
+    pub fn apply_sgr(&mut self, params: &[i64]) {
+        //`CSI m` is equivalent to `CSI 0 m`
+        if params.is_empty() {
+            self.reset();
+            return;
+        }
+
+        let mut idx = 0;
+        while idx < params.len() {
+            match params[idx] {
+                0 => self.reset(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.reverse = true,
+                9 => self.strikethrough = true,
+                21 => self.underline = true, //double underline, rendered as single underline
+                22 => { self.bold = false; self.dim = false },
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.reverse = false,
+                29 => self.strikethrough = false,
+                p @ 30...37 => self.foreground = Some(Color::Palette((p - 30) as u8)),
+                38 => {
+                    let (color, consumed) = parse_extended_color(&params[idx+1..]);
+                    if color.is_some() {
+                        self.foreground = color;
+                    }
+                    idx += consumed;
+                },
+                39 => self.foreground = None,
+                p @ 40...47 => self.background = Some(Color::Palette((p - 40) as u8)),
+                48 => {
+                    let (color, consumed) = parse_extended_color(&params[idx+1..]);
+                    if color.is_some() {
+                        self.background = color;
+                    }
+                    idx += consumed;
+                },
+                49 => self.background = None,
+                p @ 90...97 => self.foreground = Some(Color::Palette((p - 90 + 8) as u8)),
+                p @ 100...107 => self.background = Some(Color::Palette((p - 100 + 8) as u8)),
+                p => info!("unsupported SGR parameter: {}", p),
+            }
+            idx += 1;
+        }
+    }
+}
+
+///Parses the arguments following a 38 or 48 SGR parameter. Returns the parsed
+///color (if any) and the number of parameters consumed.
+fn parse_extended_color(params: &[i64]) -> (Option<Color>, usize) {
+    match params.get(0) {
+        Some(&5) => match params.get(1) {
+            Some(&n) if n >= 0 && n < 256 => (Some(Color::Palette(n as u8)), 2),
+            Some(_) => (None, 2),
+            None => (None, 1),
+        },
+        Some(&2) => {
+            if params.len() < 4 {
+                return (None, params.len());
+            }
+            let clamp = |v: i64| if v < 0 { 0 } else if v > 255 { 255 } else { v as u8 };
+            (Some(Color::RGB(clamp(params[1]), clamp(params[2]), clamp(params[3]))), 4)
+        },
+        Some(_) => (None, 1),
+        None => (None, 0),
+    }
+}
+
+///A range of a section's text that is rendered with a non-default style. The
+///indexes are byte offsets into `Section::text()` or `Chunk::text()`,
+///respectively.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StyledSpan {
+    pub start: usize,
+    pub end: usize,
+    pub style: Style,
+}
@@ -0,0 +1,201 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use std;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use nix;
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::pty::{openpty, Winsize};
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use nix::unistd;
+
+///A pseudo-terminal for clients that need their stdio to be a TTY. The client
+///runs on the slave side, and the master side is relayed into the client's
+///stdio connection to the VT6 server.
+pub struct Pty {
+    pub master: RawFd,
+    pub slave: RawFd,
+}
+
+impl Pty {
+    ///Opens a pseudo-terminal with the given size (columns, rows). Later
+    ///changes of the size are applied with set_size().
+    pub fn open(size: (usize, usize)) -> nix::Result<Pty> {
+        let result = openpty(Some(&make_winsize(size)), None)?;
+        let pty = Pty { master: result.master, slave: result.slave };
+
+        //user input is already shown by 6term itself while it is being edited,
+        //so the terminal must not echo it again
+        let mut attrs = termios::tcgetattr(pty.slave)?;
+        attrs.local_flags.remove(LocalFlags::ECHO);
+        termios::tcsetattr(pty.slave, SetArg::TCSANOW, &attrs)?;
+
+        Ok(pty)
+    }
+
+    pub fn close(self) {
+        let _ = unistd::close(self.master);
+        let _ = unistd::close(self.slave);
+    }
+
+    ///Called in the child process after fork() to make the slave side the
+    ///controlling terminal of a new session. (The client becomes the leader of
+    ///a new process group as a side effect.)
+    pub fn attach(&self) -> nix::Result<()> {
+        unistd::setsid()?;
+        let result = unsafe { libc::ioctl(self.slave, libc::TIOCSCTTY as _, 0) };
+        nix::errno::Errno::result(result).map(|_| ())
+    }
+
+    ///Called in the parent process after fork(). Closes the slave side (which
+    ///is only needed by the client) and copies data between the master side
+    ///and the given stream in two threads until either side is closed, or
+    ///until Relay::stop() is called.
+    pub fn relay(self, stream: UnixStream) -> std::io::Result<Relay> {
+        let _ = unistd::close(self.slave);
+        let mut master_out = unsafe { File::from_raw_fd(self.master) };
+        let mut master_in = master_out.try_clone()?;
+        let mut stream_in = stream.try_clone()?;
+        let mut stream_out = stream.try_clone()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        //client output -> server
+        let output_stop = stop.clone();
+        let output_done_tx = done_tx.clone();
+        let output_thread = std::thread::spawn(move || {
+            //reading from the master fails with EIO once the client has
+            //closed all its handles on the slave side
+            let _ = copy_until_stopped(&mut master_out, &mut stream_out, &output_stop);
+            let _ = stream_out.shutdown(Shutdown::Both);
+            let _ = output_done_tx.send(());
+        });
+        //user input -> client
+        let input_thread = std::thread::spawn(move || {
+            let _ = copy(&mut stream_in, &mut master_in);
+            let _ = done_tx.send(());
+        });
+
+        Ok(Relay {
+            threads: vec![output_thread, input_thread],
+            done_rx, stop, stream,
+        })
+    }
+}
+
+///The threads started by Pty::relay().
+pub struct Relay {
+    threads: Vec<JoinHandle<()>>,
+    ///Receives a message from each thread when it is done.
+    done_rx: mpsc::Receiver<()>,
+    ///Tells the output thread to stop, even if the client still holds on to
+    ///the slave side.
+    stop: Arc<AtomicBool>,
+    ///Shutting this down stops the input thread.
+    stream: UnixStream,
+}
+
+impl Relay {
+    ///Stops both threads, and joins them unless they do not finish until
+    ///`deadline`. Returns whether they were joined.
+    pub fn stop(self, deadline: Instant) -> bool {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        for _ in 0..self.threads.len() {
+            let now = Instant::now();
+            if now >= deadline || self.done_rx.recv_timeout(deadline - now).is_err() {
+                return false;
+            }
+        }
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+        true
+    }
+}
+
+///Changes the size (columns, rows) of the pseudo-terminal with the given master
+///side. The kernel sends SIGWINCH to its foreground process group.
+pub fn set_size(master: RawFd, size: (usize, usize)) -> nix::Result<()> {
+    let winsize = make_winsize(size);
+    let result = unsafe { libc::ioctl(master, libc::TIOCSWINSZ as _, &winsize) };
+    nix::errno::Errno::result(result).map(|_| ())
+}
+
+fn make_winsize(size: (usize, usize)) -> Winsize {
+    Winsize {
+        ws_col: size.0 as u16,
+        ws_row: size.1 as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+///Like std::io::copy(), but flushes after each read since the data is
+///interactive.
+fn copy<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> std::io::Result<()> {
+    let mut buf = [0u8; 4096];
+    while copy_once(reader, writer, &mut buf)? {}
+    Ok(())
+}
+
+///How often copy_until_stopped() checks whether it shall stop.
+const STOP_POLL_INTERVAL_MS: i32 = 100;
+
+///Like copy(), but also returns when `stop` is set.
+fn copy_until_stopped<R: Read + AsRawFd, W: Write>(reader: &mut R, writer: &mut W, stop: &AtomicBool) -> std::io::Result<()> {
+    let mut buf = [0u8; 4096];
+    while !stop.load(Ordering::SeqCst) {
+        let mut fds = [PollFd::new(reader.as_raw_fd(), EventFlags::POLLIN)];
+        match poll(&mut fds, STOP_POLL_INTERVAL_MS) {
+            Ok(0) | Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Ok(_) => {},
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+        }
+        if !copy_once(reader, writer, &mut buf)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+///Copies whatever can be read at once. Returns false at EOF.
+fn copy_once<R: Read, W: Write>(reader: &mut R, writer: &mut W, buf: &mut [u8]) -> std::io::Result<bool> {
+    let n = loop {
+        match reader.read(buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => break n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    writer.write_all(&buf[..n])?;
+    writer.flush()?;
+    Ok(true)
+}
@@ -17,16 +17,22 @@
 *******************************************************************************/
 
 use std;
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::sync::mpsc;
+use nix::sys::socket::{getsockopt, sockopt};
+use nix::unistd::getuid;
 use tokio::prelude::*;
+use tokio::timer::Interval;
 use vt6;
 use vt6::server::core::{StreamMode, StreamState, Tracker};
 use vt6tokio;
 use vt6tokio::server::core::IncomingEvent;
 
+use handler;
 use model;
 use window;
 
@@ -34,29 +40,44 @@ pub enum OutgoingEvent {
     RedrawWindow,
 }
 
+///Runs the VT6 server. Clients connect to the socket at `socket_path` (which
+///is advertised to them in $VT6). The stdio of the clients that we spawn is
+///connected to a separate socket at `stdio_socket_path` instead, which is how
+///we can tell stdio connections from other connections.
 pub fn make_server_future(
     socket_path: PathBuf,
+    stdio_socket_path: PathBuf,
     incoming_rx: mpsc::Receiver<IncomingEvent>,
     model: Arc<Mutex<model::Document>>,
     window_handle: window::WindowHandle
 ) -> std::io::Result<impl Future<Item = (), Error = ()>> {
-    let handler = vt6::server::RejectHandler {};
-    let handler = vt6::server::core::Handler::new(handler);
-
     let (outgoing_tx, outgoing_rx) = mpsc::channel(10);
 
-    //the first constituent future is the vt6tokio server
+    //the first constituent future is the vt6tokio server for message
+    //connections, which does not receive user input (the sender is kept alive
+    //until the server ends, so that its input stream does not end either)
+    let (no_input_tx, no_input_rx) = mpsc::channel(1);
     let future1 = vt6tokio::server::core::Server::<Connection, _>::new(
-        handler,
+        handler::new(),
         socket_path,
+        no_input_rx,
+        outgoing_tx.clone(),
+        ConnectionContext { model: model.clone(), is_stdio: false },
+    )?.map(move |_| drop(no_input_tx));
+
+    //the second constituent future is the vt6tokio server for stdio
+    //connections, which delivers the user input
+    let future2 = vt6tokio::server::core::Server::<Connection, _>::new(
+        handler::new(),
+        stdio_socket_path,
         incoming_rx,
         outgoing_tx,
-        model,
+        ConnectionContext { model, is_stdio: true },
     )?;
 
-    //the second constituent future consumes the outgoing_rx and emits
+    //the third constituent future consumes the outgoing_rx and emits
     //events onto the GTK eventloop
-    let future2 = outgoing_rx.for_each(move |event| {
+    let future3 = outgoing_rx.for_each(move |event| {
         use self::OutgoingEvent::*;
         match event {
             RedrawWindow => window_handle.redraw(),
@@ -64,8 +85,29 @@ pub fn make_server_future(
         Ok(())
     });
 
-    //we run both futures to completion, but return () instead of ((), ())
-    Ok(future1.join(future2).map(|_| ()))
+    //we run all futures to completion, but return () instead of ((), (), ())
+    Ok(future1.join3(future2, future3).map(|_| ()))
+}
+
+///How often wait_for_drain() checks whether all connections have been closed.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+///Returns a future that resolves once all connections have been closed. On
+///shutdown, this runs alongside the server future after the sockets have been
+///removed, so that no new connections come in.
+pub fn wait_for_drain(model: Arc<Mutex<model::Document>>) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), DRAIN_POLL_INTERVAL)
+        .map_err(|e| error!("drain timer failed: {}", e))
+        .take_while(move |_| Ok(model.lock().unwrap().open_connections() > 0))
+        .for_each(|_| Ok(()))
+}
+
+///What a Connection needs to know when it is created. Each vt6tokio server has
+///its own, so that connections know which socket they came in on.
+#[derive(Clone)]
+pub struct ConnectionContext {
+    model: Arc<Mutex<model::Document>>,
+    is_stdio: bool,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -73,27 +115,43 @@ pub fn make_server_future(
 
 pub struct Connection {
     id: u32,
+    ///Identifies this connection in the model. Unlike `self.id`, which is only
+    ///unique within one vt6tokio server, this is unique across both servers.
+    model_id: u32,
     tracker: Tracker,
     stream_state: StreamState,
+    output_parser: model::OutputParser,
+    ///Value of the term.output-protected property.
+    output_protected: bool,
     model: Arc<Mutex<model::Document>>,
     event_tx: mpsc::Sender<OutgoingEvent>,
+    ///Messages that the server sends on its own initiative (e.g. sig events)
+    ///go through this channel. The receiver is handed to vt6tokio by
+    ///take_server_messages().
+    server_message_tx: mpsc::Sender<Vec<u8>>,
+    server_message_rx: Option<mpsc::Receiver<Vec<u8>>>,
 }
 
 impl vt6tokio::server::core::Connection for Connection {
-    type ModelRef = Arc<Mutex<model::Document>>;
+    type ModelRef = ConnectionContext;
     type OutgoingEvent = OutgoingEvent;
 
     fn new(
-        id: u32, model: Arc<Mutex<model::Document>>, event_tx: mpsc::Sender<OutgoingEvent>
+        id: u32, context: ConnectionContext, event_tx: mpsc::Sender<OutgoingEvent>
     ) -> Connection
     {
-        //first connection is the initial stdio
-        let mode = if id == 0 { StreamMode::Stdio } else { StreamMode::Message };
+        let mode = if context.is_stdio { StreamMode::Stdio } else { StreamMode::Message };
+        let model = context.model;
+        let model_id = model.lock().unwrap().register_connection();
+        let (server_message_tx, server_message_rx) = mpsc::channel(10);
 
         Connection {
-            id, model, event_tx,
+            id, model_id, model, event_tx, server_message_tx,
+            server_message_rx: Some(server_message_rx),
             tracker: Default::default(),
             stream_state: StreamState::enter(mode),
+            output_parser: model::OutputParser::new(),
+            output_protected: false,
         }
     }
 
@@ -101,21 +159,68 @@ impl vt6tokio::server::core::Connection for Connection {
         self.id
     }
 
+    fn accept(_context: &ConnectionContext, peer_fd: RawFd) -> bool {
+        is_same_user(peer_fd)
+    }
+
+    fn take_server_messages(&mut self) -> Option<mpsc::Receiver<Vec<u8>>> {
+        self.server_message_rx.take()
+    }
+
     fn handle_standard_output(&mut self, bytes_received: &[u8]) {
         let mut document = self.model.lock().unwrap();
-        //append the received output to bottom-most output section
-        if let Some(section) = document.sections.last_mut() {
-            //TODO respect term.output-protected property
-            section.append_output(bytes_received, false);
+        document.append_output(&mut self.output_parser, bytes_received, self.output_protected);
+        document.enforce_scrollback();
+        drop(document);
+
+        //When the channel is full, there are redraw requests queued already that
+        //will pick up this output as well, so there is no need to wait.
+        if let Err(e) = self.event_tx.try_send(OutgoingEvent::RedrawWindow) {
+            if e.is_disconnected() {
+                error!("cannot request redraw: GUI event channel disconnected");
+            }
         }
-        //TODO check return value from try_send
-        self.event_tx.try_send(OutgoingEvent::RedrawWindow).unwrap();
+    }
+}
+
+///Checks that the peer of an accepted connection runs as the same user as we
+///do. The socket directory is only accessible to us anyway, but this also
+///covers clients that have been handed a connected socket by someone else.
+fn is_same_user(peer_fd: RawFd) -> bool {
+    match getsockopt(peer_fd, sockopt::PeerCredentials) {
+        Ok(creds) => {
+            let allowed = creds.uid() == getuid().as_raw();
+            if !allowed {
+                warn!("rejecting connection from process {} of user {}", creds.pid(), creds.uid());
+            }
+            allowed
+        },
+        Err(e) => {
+            error!("rejecting connection: cannot get peer credentials: {}", e);
+            false
+        },
     }
 }
 
 impl vt6::server::Connection for Connection {
     fn enable_module(&mut self, name: &str, version: vt6::common::core::ModuleVersion) {
-        self.tracker.enable_module(name, version)
+        self.tracker.enable_module(name, version);
+        if name == "sig" {
+            //from now on, control keys are delivered to this connection
+            let mut tx = self.server_message_tx.clone();
+            let max_length = vt6::server::core::Connection::max_server_message_length(self);
+            let receiver: model::SignalReceiver = Box::new(move |signal| {
+                let mut buf = vec![0; max_length];
+                match handler::sig::format_event(signal, &mut buf) {
+                    Some(length) => {
+                        buf.truncate(length);
+                        tx.try_send(buf).is_ok()
+                    },
+                    None => false,
+                }
+            });
+            self.model.lock().unwrap().set_signal_receiver(self.model_id, Some(receiver));
+        }
     }
     fn is_module_enabled(&self, name: &str) -> Option<vt6::common::core::ModuleVersion> {
         self.tracker.is_module_enabled(name)
@@ -133,3 +238,22 @@ impl vt6::server::core::Connection for Connection {
     fn max_server_message_length(&self) -> usize { 1024 }
     fn max_client_message_length(&self) -> usize { 1024 }
 }
+
+impl handler::Connection for Connection {
+    fn output_protected(&self) -> bool { self.output_protected }
+    fn set_output_protected(&mut self, value: bool) { self.output_protected = value; }
+    //the input modes are tracked in the model only, since they depend on all
+    //connections together
+    fn input_immediate(&self) -> bool { self.model.lock().unwrap().has_immediate_input(self.model_id) }
+    fn set_input_immediate(&mut self, value: bool) { self.model.lock().unwrap().set_immediate_input(self.model_id, value); }
+    fn input_echo(&self) -> bool { self.model.lock().unwrap().has_input_echo(self.model_id) }
+    fn set_input_echo(&mut self, value: bool) { self.model.lock().unwrap().set_input_echo(self.model_id, value); }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        //the input modes and sig events end with the connection that requested
+        //them (unless another connection requests them as well)
+        self.model.lock().unwrap().unregister_connection(self.model_id);
+    }
+}
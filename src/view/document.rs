@@ -17,57 +17,326 @@
 *******************************************************************************/
 
 use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
 use cairo;
-use gtk::{self, WidgetExt};
+use gtk::{self, AdjustmentExt, WidgetExt};
+use pango::{self, LayoutExt};
+use pangocairo;
 
 use model;
 use view;
+use view::palette;
+
+///Distance (in pixels) that the viewport moves per mouse wheel step.
+pub const SCROLL_STEP: f64 = 48.;
+
+///How many Pango layouts (one per chunk, see view::Section) may be cached at
+///the same time. When there are more, the least recently painted ones are
+///dropped.
+const MAX_CACHED_LAYOUTS: usize = 256;
+
+///How often render() lays out newly visible chunks and repositions the viewport
+///before painting. More than one pass is needed when laying out moves other
+///chunks with estimated heights into the viewport.
+const MAX_LAYOUT_PASSES: usize = 3;
 
 pub struct Document {
     model: Arc<Mutex<model::Document>>,
     sections: HashMap<model::SectionID, view::Section>,
+    ///Describes the viewport. Its value is the offset (in pixels) of the upper
+    ///edge of the viewport from the top of the document.
+    adjustment: gtk::Adjustment,
+    ///Counts calls to render(). Used to find least recently painted layouts.
+    frame: u64,
+    ///The value of model.removed_sections() when the views of removed sections
+    ///were last dropped.
+    removed_sections: u64,
+    ///Shown at the top of the document when history was discarded because of
+    ///the scrollback policy. Created on first use.
+    trimmed_marker: Option<pango::Layout>,
+    ///Render state for the alternate screen (if any).
+    grid: view::Grid,
+    ///Height of a single line of text, used to estimate the height of chunks
+    ///that have not been laid out yet. Measured on first use.
+    line_height: Option<i32>,
 }
 
 impl Document {
-    pub fn new(model: Arc<Mutex<model::Document>>) -> Document {
+    pub fn new(model: Arc<Mutex<model::Document>>, adjustment: gtk::Adjustment) -> Document {
         Document {
             model: model,
             sections: HashMap::new(),
+            adjustment: adjustment,
+            frame: 0,
+            removed_sections: 0,
+            trimmed_marker: None,
+            grid: view::Grid::new(),
+            line_height: None,
         }
     }
 
     pub fn render(&mut self, canvas: &gtk::DrawingArea, ctx: &cairo::Context) {
-        let model = self.model.lock().unwrap();
+        let mut model = self.model.lock().unwrap();
         let pixel_width = canvas.get_allocated_width();
 
         //draw background
-        ctx.set_source_rgb(0., 0., 0.);
+        palette::set_source_color(ctx, palette::DEFAULT_BACKGROUND);
         ctx.paint();
 
+        //full-screen clients need to know how much space they have
+        let (cols, rows) = self.grid.screen_size(canvas);
+        if model.screen_size() != (cols, rows) {
+            model.set_screen_size(cols, rows);
+        }
+
+        //while the alternate screen is active, it replaces the sections
+        if let Some(ref screen) = model.screen {
+            self.grid.render(screen, canvas, ctx);
+            if !model.input_queue.is_empty() {
+                render_input_queue_status(&model.input_queue, canvas, ctx);
+            }
+            return;
+        }
+
+        self.frame += 1;
+
+        //forget about sections that were removed from the model (comparing the
+        //number of sections is not enough since new sections may have been
+        //added in the meantime)
+        if self.removed_sections != model.removed_sections() {
+            self.removed_sections = model.removed_sections();
+            let ids: HashSet<model::SectionID> = model.sections.iter().map(|s| s.id()).collect();
+            let mut removed_height = 0;
+            self.sections.retain(|id, s| {
+                let keep = ids.contains(id);
+                if !keep {
+                    removed_height += s.height();
+                }
+                keep
+            });
+            //keep the viewport on the same content when scrolled up
+            if !is_at_bottom(&self.adjustment) {
+                scroll_by(&self.adjustment, -removed_height as f64);
+            }
+        }
+
+        //layout the marker for discarded history, if needed
+        let marker_height = if model.is_trimmed() {
+            let marker = self.trimmed_marker.get_or_insert_with(|| {
+                let layout = canvas.create_pango_layout(Some("older output has been discarded")).unwrap();
+                layout.set_alignment(pango::Alignment::Center);
+                let attr_list = pango::AttrList::new();
+                if let Some(attr) = pango::Attribute::new_style(pango::Style::Italic) {
+                    attr_list.insert(attr);
+                }
+                layout.set_attributes(&attr_list);
+                layout
+            });
+            marker.set_width(pixel_width * pango::SCALE);
+            marker.get_pixel_extents().1.height
+        } else {
+            0
+        };
+
+        //find the total height of the document (without laying out anything,
+        //so sections outside the viewport only have estimated heights)
+        let line_height = *self.line_height.get_or_insert_with(|| {
+            let layout = canvas.create_pango_layout(Some("X")).unwrap();
+            layout.get_pixel_extents().1.height
+        });
+        let mut heights = Vec::with_capacity(model.sections.len());
+        for section in model.sections.iter() {
+            let section_view = self.sections.entry(section.id()).or_insert_with(|| {
+                view::Section::new(section)
+            });
+            heights.push(section_view.update(section, pixel_width, line_height));
+        }
+        let page_size = canvas.get_allocated_height() as f64;
+        let mut offset = self.update_viewport(total_height(marker_height, &heights), page_size, 0);
+
+        //lay out the sections in the viewport; when their actual heights differ
+        //from the estimates, the viewport needs to be adjusted
+        for _ in 0..MAX_LAYOUT_PASSES {
+            let (shift, changed) = layout_visible(&mut self.sections, &model.sections, &mut heights, canvas,
+                marker_height, offset, offset + page_size);
+            if !changed {
+                break;
+            }
+            offset = self.update_viewport(total_height(marker_height, &heights), page_size, shift);
+        }
+
+        //find the part of the document that needs to be repainted
+        let (_, clip_top, _, clip_bottom) = ctx.clip_extents();
+        let visible_top = offset + clip_top;
+        let visible_bottom = offset + clip_bottom;
+
         //draw sections
-        ctx.set_source_rgb(1., 1., 1.);
+        palette::set_source_color(ctx, palette::DEFAULT_FOREGROUND);
         ctx.identity_matrix();
+        ctx.translate(0., -offset);
+
+        if let Some(ref marker) = self.trimmed_marker {
+            if marker_height > 0 && (marker_height as f64) > visible_top {
+                ctx.save();
+                palette::set_source_color(ctx, palette::TRIMMED_MARKER_FOREGROUND);
+                ctx.move_to(0., 0.);
+                pangocairo::functions::show_layout(ctx, marker);
+                ctx.restore();
+            }
+        }
+        ctx.translate(0., marker_height as f64);
 
         let section_count = model.sections.len();
+        let mut needs_repaint = false;
+        let mut section_top = marker_height as f64;
         for (idx, section) in model.sections.iter().enumerate() {
-            let mut section_view = self.sections.entry(section.id()).or_insert_with(|| {
-                view::Section::new(section, canvas)
-            });
-            let height = section_view.prepare_rendering(section, pixel_width);
-            let show_cursor = idx == section_count - 1;
-            section_view.render(section, ctx, show_cursor);
-            ctx.translate(0., height as f64);
+            let section_bottom = section_top + heights[idx] as f64;
+            if section_top >= visible_bottom {
+                break;
+            }
+            if section_bottom > visible_top {
+                let section_view = self.sections.get_mut(&section.id()).unwrap();
+                let show_cursor = idx == section_count - 1;
+                needs_repaint |= section_view.render(section, canvas, ctx, show_cursor, self.frame,
+                    visible_top - section_top, visible_bottom - section_top);
+            }
+            ctx.translate(0., heights[idx] as f64);
+            section_top = section_bottom;
+        }
+
+        if !model.input_queue.is_empty() {
+            render_input_queue_status(&model.input_queue, canvas, ctx);
+        }
+
+        evict_layouts(&mut self.sections);
+
+        //some chunks had to be laid out while painting, so the positions of
+        //everything below them were off
+        if needs_repaint {
+            canvas.queue_draw();
         }
+    }
+
+    ///Updates the adjustment to match the current document and canvas size, and
+    ///returns the scroll offset to use for rendering. While the viewport is
+    ///scrolled to the bottom, it stays at the bottom when new output arrives.
+    ///Otherwise, it moves by `shift` to compensate for height changes above it.
+    fn update_viewport(&self, total_height: f64, page_size: f64, shift: i32) -> f64 {
+        let adj = &self.adjustment;
+        let was_at_bottom = is_at_bottom(adj);
+
+        let upper = total_height.max(page_size);
+        let max_value = upper - page_size;
+        let value = if was_at_bottom { max_value } else { (adj.get_value() + shift as f64).max(0.).min(max_value) };
+        adj.configure(value, 0., upper, SCROLL_STEP, page_size * 0.9, page_size);
+        value
+    }
+}
+
+fn total_height(marker_height: i32, heights: &[i32]) -> f64 {
+    (marker_height + heights.iter().sum::<i32>()) as f64
+}
 
-        /* TODO kept for later reference
-        let attr_list = pango::AttrList::new();
-        let mut attr = pango::Attribute::new_strikethrough(true).unwrap();
-        attr.set_start_index(6);
-        attr.set_end_index(10);
-        attr_list.insert(attr);
-        layout.set_attributes(&attr_list);
-        */
+///Lays out the chunks of all sections that intersect the viewport (from `top`
+///to `bottom` in document coordinates), and updates `heights` accordingly.
+///Returns by how much the viewport needs to move to stay on the same content,
+///and whether any height changed.
+fn layout_visible(
+    views: &mut HashMap<model::SectionID, view::Section>, sections: &[model::Section], heights: &mut [i32],
+    canvas: &gtk::DrawingArea, marker_height: i32, top: f64, bottom: f64
+) -> (i32, bool) {
+    let mut top = top;
+    let mut bottom = bottom;
+    let mut shift = 0;
+    let mut changed = false;
+
+    let mut section_top = marker_height as f64;
+    for (idx, section) in sections.iter().enumerate() {
+        if section_top >= bottom {
+            break;
+        }
+        if section_top + heights[idx] as f64 > top {
+            let section_view = views.get_mut(&section.id()).unwrap();
+            let section_shift = section_view.layout_visible(section, canvas, top - section_top, bottom - section_top);
+            shift += section_shift;
+            top += section_shift as f64;
+            bottom += section_shift as f64;
+            if section_view.height() != heights[idx] {
+                heights[idx] = section_view.height();
+                changed = true;
+            }
+        }
+        section_top += heights[idx] as f64;
+    }
+    (shift, changed)
+}
+
+fn is_at_bottom(adjustment: &gtk::Adjustment) -> bool {
+    adjustment.get_value() >= adjustment.get_upper() - adjustment.get_page_size() - 1.
+}
+
+///Scrolls the viewport by the given amount of pixels (negative values scroll up).
+pub fn scroll_by(adjustment: &gtk::Adjustment, delta: f64) {
+    let max_value = adjustment.get_upper() - adjustment.get_page_size();
+    let value = (adjustment.get_value() + delta).max(0.).min(max_value);
+    adjustment.set_value(value);
+}
+
+///Scrolls the viewport to the bottom, which re-enables auto-follow.
+pub fn scroll_to_bottom(adjustment: &gtk::Adjustment) {
+    adjustment.set_value(adjustment.get_upper() - adjustment.get_page_size());
+}
+
+///Shows user input that has not been delivered to the client yet in a status bar
+///at the bottom of the viewport.
+fn render_input_queue_status(queue: &model::InputQueue, canvas: &gtk::DrawingArea, ctx: &cairo::Context) {
+    let lines = queue.lines();
+    let mut text = if queue.is_full() {
+        format!("input queue full, waiting for client ({} lines pending)", lines.len())
+    } else {
+        format!("waiting for client ({} lines pending)", lines.len())
+    };
+    for line in lines.iter() {
+        text.push('\n');
+        text.push_str(line.trim_right_matches('\n'));
+    }
+
+    let layout = canvas.create_pango_layout(Some(text.as_str())).unwrap();
+    layout.set_width(canvas.get_allocated_width() * pango::SCALE);
+    layout.set_height(-3); //show at most three lines
+    layout.set_ellipsize(pango::EllipsizeMode::End);
+    let height = layout.get_pixel_extents().1.height as f64;
+    let top = canvas.get_allocated_height() as f64 - height;
+
+    ctx.save();
+    ctx.identity_matrix();
+    let status_color = if queue.is_full() { palette::QUEUE_FULL_BACKGROUND } else { palette::QUEUE_BACKGROUND };
+    palette::set_source_color(ctx, status_color);
+    ctx.rectangle(0., top, canvas.get_allocated_width() as f64, height);
+    ctx.fill();
+    palette::set_source_color(ctx, palette::USER_INPUT_FOREGROUND);
+    ctx.move_to(0., top);
+    pangocairo::functions::show_layout(ctx, &layout);
+    ctx.restore();
+}
+
+///Drops the least recently painted Pango layouts when there are more than
+///MAX_CACHED_LAYOUTS of them.
+fn evict_layouts(sections: &mut HashMap<model::SectionID, view::Section>) {
+    let mut cached: Vec<(u64, model::SectionID, Option<u64>)> = sections.iter()
+        .flat_map(|(id, s)| s.cached_layouts().into_iter().map(move |(last_painted, chunk_id)| (last_painted, *id, chunk_id)))
+        .collect();
+    if cached.len() <= MAX_CACHED_LAYOUTS {
+        return;
+    }
+
+    cached.sort_by_key(|&(last_painted, _, _)| last_painted);
+    let excess = cached.len() - MAX_CACHED_LAYOUTS;
+    for &(_, id, chunk_id) in cached.iter().take(excess) {
+        if let Some(section) = sections.get_mut(&id) {
+            section.evict_layout(chunk_id);
+        }
     }
 }
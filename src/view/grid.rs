@@ -0,0 +1,125 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use cairo;
+use gtk::{self, WidgetExt};
+use pango::{self, LayoutExt};
+use pangocairo;
+
+use model;
+use view;
+use view::palette;
+
+///The render state for a model::GridSection. Since the grid is only shown while
+///it is active and changes all over the place, layouts are not cached.
+pub struct Grid {
+    ///Grids always use a monospace font, since cursor addressing does not work
+    ///otherwise.
+    font: pango::FontDescription,
+    ///Size of a single character cell in pixels. Measured on first use.
+    cell_size: Option<(i32, i32)>,
+}
+
+impl Grid {
+    pub fn new() -> Grid {
+        Grid {
+            font: pango::FontDescription::from_string("Monospace"),
+            cell_size: None,
+        }
+    }
+
+    ///Returns how many character cells (columns, rows) fit on the canvas.
+    pub fn screen_size(&mut self, canvas: &gtk::DrawingArea) -> (usize, usize) {
+        let (cell_width, cell_height) = self.cell_size(canvas);
+        let cols = (canvas.get_allocated_width() / cell_width).max(1);
+        let rows = (canvas.get_allocated_height() / cell_height).max(1);
+        (cols as usize, rows as usize)
+    }
+
+    fn cell_size(&mut self, canvas: &gtk::DrawingArea) -> (i32, i32) {
+        if let Some(size) = self.cell_size {
+            return size;
+        }
+        let layout = canvas.create_pango_layout(Some("M")).unwrap();
+        layout.set_font_description(Some(&self.font));
+        let extents = layout.get_pixel_extents().1;
+        let size = (extents.width.max(1), extents.height.max(1));
+        self.cell_size = Some(size);
+        size
+    }
+
+    ///Paints the grid in the upper left corner of the canvas. Each row is laid
+    ///out separately, so that rows always line up with the cell size.
+    pub fn render(&mut self, model: &model::GridSection, canvas: &gtk::DrawingArea, ctx: &cairo::Context) {
+        let (cell_width, cell_height) = self.cell_size(canvas);
+
+        ctx.save();
+        ctx.identity_matrix();
+        palette::set_source_color(ctx, palette::DEFAULT_FOREGROUND);
+
+        for (idx, row) in model.rows().iter().enumerate() {
+            let (text, spans) = row_text(row);
+            let layout = canvas.create_pango_layout(Some(text.as_str())).unwrap();
+            layout.set_font_description(Some(&self.font));
+            layout.set_attributes(&view::make_attr_list(&spans, None));
+            ctx.move_to(0., (idx as i32 * cell_height) as f64);
+            pangocairo::functions::show_layout(ctx, &layout);
+        }
+
+        if model.cursor_visible() {
+            let (row, col) = model.cursor();
+            ctx.rectangle(
+                (col as i32 * cell_width) as f64,
+                (row as i32 * cell_height) as f64,
+                1.,
+                cell_height as f64,
+            );
+            ctx.fill();
+        }
+
+        ctx.restore();
+    }
+}
+
+///Converts a row of cells into text and styled spans. The second cell of a
+///double-width character does not contribute any text, since the monospace
+///font renders the character itself across both cells.
+fn row_text(row: &[model::Cell]) -> (String, Vec<model::StyledSpan>) {
+    let mut text = String::with_capacity(row.len());
+    let mut spans: Vec<model::StyledSpan> = Vec::new();
+    for cell in row {
+        if cell.continuation {
+            continue;
+        }
+        let start = text.len();
+        text.push(cell.ch);
+        text.push_str(&cell.combining);
+        let end = text.len();
+        if cell.style.is_default() {
+            continue;
+        }
+        if let Some(last) = spans.last_mut() {
+            if last.end == start && last.style == cell.style {
+                last.end = end;
+                continue;
+            }
+        }
+        spans.push(model::StyledSpan { start, end, style: cell.style });
+    }
+    (text, spans)
+}
@@ -17,7 +17,10 @@
 *******************************************************************************/
 
 pub mod document;
+pub mod grid;
+pub mod palette;
 pub mod section;
 
 pub use self::document::*;
+pub use self::grid::*;
 pub use self::section::*;
@@ -0,0 +1,92 @@
+/*******************************************************************************
+*
+* Copyright 2018 Stefan Majewsky <majewsky@gmx.net>
+*
+* This program is free software: you can redistribute it and/or modify it under
+* the terms of the GNU General Public License as published by the Free Software
+* Foundation, either version 3 of the License, or (at your option) any later
+* version.
+*
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY
+* WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+* A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along with
+* this program. If not, see <http://www.gnu.org/licenses/>.
+*
+*******************************************************************************/
+
+use cairo;
+
+use model;
+
+pub type RGB = (u8, u8, u8);
+
+pub const DEFAULT_FOREGROUND: RGB = (0xFF, 0xFF, 0xFF);
+pub const DEFAULT_BACKGROUND: RGB = (0x00, 0x00, 0x00);
+///Background for output that was received while term.output-protected was set.
+pub const PROTECTED_BACKGROUND: RGB = (0x26, 0x26, 0x3A);
+///Foreground for the marker that indicates discarded history.
+pub const TRIMMED_MARKER_FOREGROUND: RGB = (0x80, 0x80, 0x80);
+///Background for the status bar showing undelivered user input.
+pub const QUEUE_BACKGROUND: RGB = (0x30, 0x30, 0x30);
+///Background for the status bar when the input queue is full.
+pub const QUEUE_FULL_BACKGROUND: RGB = (0x80, 0x20, 0x20);
+///Foreground for user input (both pending and submitted).
+pub const USER_INPUT_FOREGROUND: RGB = (0xFF, 0xD7, 0x5F);
+
+///The 16 classic ANSI colors (normal and bright variants), using the xterm
+///default values.
+const ANSI_COLORS: [RGB; 16] = [
+    (0x00, 0x00, 0x00), (0xCD, 0x00, 0x00), (0x00, 0xCD, 0x00), (0xCD, 0xCD, 0x00),
+    (0x00, 0x00, 0xEE), (0xCD, 0x00, 0xCD), (0x00, 0xCD, 0xCD), (0xE5, 0xE5, 0xE5),
+    (0x7F, 0x7F, 0x7F), (0xFF, 0x00, 0x00), (0x00, 0xFF, 0x00), (0xFF, 0xFF, 0x00),
+    (0x5C, 0x5C, 0xFF), (0xFF, 0x00, 0xFF), (0x00, 0xFF, 0xFF), (0xFF, 0xFF, 0xFF),
+];
+
+pub fn resolve(color: model::Color) -> RGB {
+    match color {
+        model::Color::RGB(r, g, b) => (r, g, b),
+        model::Color::Palette(idx @ 0...15) => ANSI_COLORS[idx as usize],
+        model::Color::Palette(idx @ 16...231) => {
+            //6x6x6 color cube
+            let idx = idx - 16;
+            let level = |v: u8| if v == 0 { 0 } else { 55 + 40 * v };
+            (level(idx / 36), level((idx / 6) % 6), level(idx % 6))
+        },
+        model::Color::Palette(idx) => {
+            //grayscale ramp
+            let v = 8 + 10 * (idx - 232);
+            (v, v, v)
+        },
+    }
+}
+
+///Computes the effective foreground and background colors for the given style,
+///taking `reverse` and `dim` into account.
+pub fn resolve_style(style: &model::Style) -> (RGB, RGB) {
+    let default_fg = if style.user_input { USER_INPUT_FOREGROUND } else { DEFAULT_FOREGROUND };
+    let mut fg = style.foreground.map(resolve).unwrap_or(default_fg);
+    let default_bg = if style.protected { PROTECTED_BACKGROUND } else { DEFAULT_BACKGROUND };
+    let mut bg = style.background.map(resolve).unwrap_or(default_bg);
+    if style.reverse {
+        ::std::mem::swap(&mut fg, &mut bg);
+    }
+    if style.dim {
+        //blend the foreground halfway into the background
+        let mix = |a: u8, b: u8| ((a as u16 + b as u16) / 2) as u8;
+        fg = (mix(fg.0, bg.0), mix(fg.1, bg.1), mix(fg.2, bg.2));
+    }
+    (fg, bg)
+}
+
+///Pango expects color channels as 16-bit values.
+pub fn to_pango_channels(color: RGB) -> (u16, u16, u16) {
+    let scale = |v: u8| (v as u16) * 0x101;
+    (scale(color.0), scale(color.1), scale(color.2))
+}
+
+pub fn set_source_color(ctx: &cairo::Context, color: RGB) {
+    let (r, g, b) = color;
+    ctx.set_source_rgb(r as f64 / 255., g as f64 / 255., b as f64 / 255.);
+}
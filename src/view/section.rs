@@ -22,59 +22,202 @@ use pango::{self, LayoutExt};
 use pangocairo;
 
 use std::cmp::max;
+use std::collections::VecDeque;
 
 use model;
+use view::palette;
 
 ///The render state for a model::Section. This is separate from model::Section
 ///because model::Section needs to implement std::marker::Send, but some things in
 ///here cannot be moved away from the GUI thread.
+///
+///Each sealed chunk of the model section has its own layout, so that new output
+///only requires laying out the open part of the section and new chunks. Chunks
+///are only laid out once they become visible. Until then, their height is
+///estimated from their line count.
 pub struct Section {
-    layout: pango::Layout,
+    ///Render state for the sealed chunks of the model section, in the same order.
+    chunks: VecDeque<ChunkLayout>,
+    ///Sum of the heights in `self.chunks`.
+    chunks_height: i32,
+    ///Render state for the open part of the model section.
+    tail: ChunkLayout,
     ///The last observed value of `model.generation`. When different form
-    ///`model.generation`, this means we need to update `self.layout` because the
+    ///`model.generation`, this means we need to update `self.tail` because the
     ///model has changed.
     layout_generation: u64,
+    ///The pixel width that the layouts were last laid out for.
+    layout_width: i32,
 }
 
 impl Section {
-    pub fn new(model: &model::Section, canvas: &gtk::DrawingArea) -> Section {
-        let layout = canvas.create_pango_layout(Some(model.text())).unwrap();
-        layout.set_wrap(pango::WrapMode::WordChar);
+    pub fn new(model: &model::Section) -> Section {
         Section {
-            layout: layout,
+            chunks: VecDeque::new(),
+            chunks_height: 0,
+            tail: ChunkLayout::new(0),
             layout_generation: model.generation(),
+            //force update on first call to update()
+            layout_width: -1,
         }
     }
 
-    ///Returns the local height that the section occupies on screen.
+    ///The height as of the last call to update() or layout_visible(). This
+    ///may include estimated heights of chunks that were not laid out yet.
+    pub fn height(&self) -> i32 {
+        self.chunks_height + self.tail.height
+    }
+
+    ///Lists the Pango layouts held by this section, as pairs of the frame
+    ///counter when the layout was last painted, and the chunk ID (or None for
+    ///the open part of the section).
+    pub fn cached_layouts(&self) -> Vec<(u64, Option<u64>)> {
+        let chunks = self.chunks.iter()
+            .filter(|c| c.layout.is_some())
+            .map(|c| (c.last_painted, Some(c.id)));
+        let tail = Some(&self.tail)
+            .filter(|c| c.layout.is_some())
+            .map(|c| (c.last_painted, None));
+        chunks.chain(tail).collect()
+    }
+
+    ///Drops the Pango layout of the given chunk (or the open part of the
+    ///section if None) to free memory. The cached height is retained.
+    pub fn evict_layout(&mut self, chunk_id: Option<u64>) {
+        let chunk = match chunk_id {
+            Some(id) => self.chunks.iter_mut().find(|c| c.id == id),
+            None => Some(&mut self.tail),
+        };
+        if let Some(chunk) = chunk {
+            chunk.layout = None;
+        }
+    }
+
+    ///Catches up with changes to the model section, and returns the (possibly
+    ///estimated) height that the section occupies on screen. This does not lay
+    ///out anything, so it is cheap even for sections that are not visible.
+    ///`line_height` is the height of a single line of text, which is used to
+    ///estimate the height of chunks that have not been laid out yet.
     ///FIXME Docs are unclear about whether this is in pixels or something
     ///else, so HiDPI rendering might be broken.
-    pub fn prepare_rendering(&mut self, model: &model::Section, pixel_width: i32) -> i32 {
-        self.layout.set_width(pixel_width * pango::SCALE);
+    pub fn update(&mut self, model: &model::Section, pixel_width: i32, line_height: i32) -> i32 {
+        if self.layout_generation == model.generation() && self.layout_width == pixel_width {
+            return self.height();
+        }
+
+        //drop chunks that were removed from the start of the model section
+        let first_id = model.chunks().front().map(|c| c.id());
+        while let Some(id) = self.chunks.front().map(|c| c.id) {
+            if first_id.map_or(false, |first_id| id >= first_id) {
+                break;
+            }
+            self.chunks_height -= self.chunks.pop_front().unwrap().height;
+        }
+
+        //new chunks are laid out when they become visible (after a width
+        //change, the existing chunks keep their old height as an estimate
+        //until then)
+        let known_count = self.chunks.len();
+        for model_chunk in model.chunks().iter().skip(known_count) {
+            let mut chunk = ChunkLayout::new(model_chunk.id());
+            chunk.estimate_height(model_chunk.text(), line_height);
+            self.chunks_height += chunk.height;
+            self.chunks.push_back(chunk);
+        }
+        self.layout_width = pixel_width;
+
         if self.layout_generation != model.generation() {
-            self.layout.set_text(&model.text());
+            self.tail.text_changed = true;
+            //once measured, the previous height is a better estimate
+            if self.tail.width < 0 {
+                self.tail.estimate_height(model.text(), line_height);
+            }
             self.layout_generation = model.generation();
         }
 
-        //Pango context may have been attached to a new Cairo context
-        self.layout.context_changed();
-        //return logical height
-        self.get_logical_extents().height
+        self.height()
+    }
+
+    ///Lays out the chunks that intersect the range from `visible_top` to
+    ///`visible_bottom` (relative to the upper edge of the section) and whose
+    ///height is only estimated. When the height of a chunk that starts above
+    ///`visible_top` changes, the viewport needs to move by the same amount to
+    ///stay on the same content; the sum of these adjustments is returned.
+    pub fn layout_visible(&mut self, model: &model::Section, canvas: &gtk::DrawingArea, visible_top: f64, visible_bottom: f64) -> i32 {
+        let pixel_width = self.layout_width;
+        let mut visible_top = visible_top;
+        let mut visible_bottom = visible_bottom;
+        let mut shift = 0;
+
+        let mut chunk_top = 0.;
+        for (chunk, model_chunk) in self.chunks.iter_mut().zip(model.chunks().iter()) {
+            if chunk_top >= visible_bottom {
+                return shift;
+            }
+            if chunk_top + chunk.height as f64 > visible_top && !chunk.is_exact(pixel_width) {
+                let delta = chunk.update(canvas, model_chunk.text(), model_chunk.spans(), None, pixel_width);
+                self.chunks_height += delta;
+                if chunk_top < visible_top {
+                    shift += delta;
+                    visible_top += delta as f64;
+                    visible_bottom += delta as f64;
+                }
+            }
+            chunk_top += chunk.height as f64;
+        }
+
+        let tail = &mut self.tail;
+        if chunk_top < visible_bottom && chunk_top + tail.height as f64 > visible_top && !tail.is_exact(pixel_width) {
+            let input_range = Some((model.output_cursor(), model.text().len()));
+            let delta = tail.update(canvas, model.text(), model.spans(), input_range, pixel_width);
+            if chunk_top < visible_top {
+                shift += delta;
+            }
+        }
+        shift
     }
 
     ///The current coordinates of the cairo::Context must be at the
-    ///upper left corner of the section.
+    ///upper left corner of the section. Only chunks intersecting the range
+    ///from `visible_top` to `visible_bottom` (relative to the upper edge of the
+    ///section) are painted. `frame` is the frame counter of view::Document,
+    ///which is used to find least recently painted layouts. Layouts of chunks
+    ///outside that range stay around until view::Document evicts them.
+    ///
+    ///Chunks should have been laid out by layout_visible() already. If one had
+    ///to be laid out here and its height changed, true is returned to indicate
+    ///that the section needs to be painted again.
     ///FIXME check with RTL text and RTL locale
-    pub fn render(&self, model: &model::Section, ctx: &cairo::Context, show_cursor: bool) {
+    pub fn render(&mut self, model: &model::Section, canvas: &gtk::DrawingArea, ctx: &cairo::Context, show_cursor: bool, frame: u64, visible_top: f64, visible_bottom: f64) -> bool {
+        let pixel_width = self.layout_width;
+        let mut height_changed = false;
+
         ctx.save();
 
-        //show_layout requires the cursor to point to the start of the baseline
-        let extents = self.get_logical_extents();
-        ctx.move_to(-extents.x as f64, -extents.y as f64);
-        pangocairo::functions::show_layout(ctx, &self.layout);
+        let mut chunk_top = 0.;
+        for (chunk, model_chunk) in self.chunks.iter_mut().zip(model.chunks().iter()) {
+            let chunk_bottom = chunk_top + chunk.height as f64;
+            if chunk_bottom > visible_top && chunk_top < visible_bottom {
+                let delta = chunk.update(canvas, model_chunk.text(), model_chunk.spans(), None, pixel_width);
+                if delta != 0 {
+                    self.chunks_height += delta;
+                    height_changed = true;
+                }
+                chunk.render(ctx, frame);
+            }
+            ctx.translate(0., chunk.height as f64);
+            chunk_top = chunk_bottom;
+        }
+
+        let input_range = Some((model.output_cursor(), model.text().len()));
+        if self.tail.update(canvas, model.text(), model.spans(), input_range, pixel_width) != 0 {
+            height_changed = true;
+        }
+        self.tail.render(ctx, frame);
 
         if show_cursor {
-            let (cursor_rect, _) = self.layout.get_cursor_pos(model.input_cursor() as i32);
+            let layout = self.tail.layout.as_ref().unwrap();
+            let (cursor_rect, _) = layout.get_cursor_pos(model.input_cursor() as i32);
             ctx.rectangle(
                 rescale_p2c(cursor_rect.x),
                 rescale_p2c(cursor_rect.y),
@@ -85,10 +228,135 @@ impl Section {
         }
 
         ctx.restore();
+        height_changed
+    }
+}
+
+///The layout for a single chunk of a section, or for the open part of a section.
+struct ChunkLayout {
+    id: u64,
+    ///None if the layout has been evicted. The height stays valid anyway.
+    layout: Option<pango::Layout>,
+    ///The pixel width that `self.height` was measured for.
+    width: i32,
+    ///Whether the text has changed since `self.height` was measured.
+    text_changed: bool,
+    ///Exact if is_exact() is true, an estimate otherwise.
+    height: i32,
+    ///The frame counter of view::Document when this chunk was last painted.
+    last_painted: u64,
+}
+
+impl ChunkLayout {
+    fn new(id: u64) -> ChunkLayout {
+        ChunkLayout { id, layout: None, width: -1, text_changed: true, height: 0, last_painted: 0 }
+    }
+
+    ///Sets a height estimate for a chunk that has not been laid out yet. This
+    ///assumes that no lines need to be wrapped.
+    fn estimate_height(&mut self, text: &str, line_height: i32) {
+        //Pango shows an empty line after a trailing line break
+        let lines = text.bytes().filter(|&b| b == b'\n').count() + 1;
+        self.height = lines as i32 * line_height;
+    }
+
+    fn is_exact(&self, pixel_width: i32) -> bool {
+        !self.text_changed && self.width == pixel_width
+    }
+
+    ///Creates the layout if necessary, and updates it if the text or the width
+    ///has changed. Returns by how much the height changed.
+    fn update(&mut self, canvas: &gtk::DrawingArea, text: &str, spans: &[model::StyledSpan], input_range: Option<(usize, usize)>, pixel_width: i32) -> i32 {
+        let is_new = self.layout.is_none();
+        let needs_text = is_new || self.text_changed;
+        let needs_width = is_new || self.width != pixel_width;
+
+        let layout = self.layout.get_or_insert_with(|| {
+            let layout = canvas.create_pango_layout(Some("")).unwrap();
+            layout.set_wrap(pango::WrapMode::WordChar);
+            layout
+        });
+        if needs_width {
+            layout.set_width(pixel_width * pango::SCALE);
+            self.width = pixel_width;
+        }
+        if needs_text {
+            layout.set_text(text);
+            layout.set_attributes(&make_attr_list(spans, input_range));
+        }
+        if !(needs_width || needs_text) {
+            return 0;
+        }
+        //Pango context may have been attached to a new Cairo context
+        layout.context_changed();
+        //remember logical height
+        let old_height = self.height;
+        self.height = layout.get_pixel_extents().1.height;
+        self.text_changed = false;
+        self.height - old_height
+    }
+
+    ///Paints the layout (which must exist) with its upper left corner at the
+    ///current coordinates of the cairo::Context.
+    fn render(&mut self, ctx: &cairo::Context, frame: u64) {
+        self.last_painted = frame;
+        let layout = self.layout.as_ref().unwrap();
+        //show_layout requires the cursor to point to the start of the baseline
+        let extents = layout.get_pixel_extents().1;
+        ctx.move_to(-extents.x as f64, -extents.y as f64);
+        pangocairo::functions::show_layout(ctx, layout);
+    }
+}
+
+///Converts styled spans into Pango attributes. `input_range` is the range of
+///pending user input, if any.
+pub fn make_attr_list(spans: &[model::StyledSpan], input_range: Option<(usize, usize)>) -> pango::AttrList {
+    let attr_list = pango::AttrList::new();
+    for span in spans {
+        insert_attributes(&attr_list, span.start, span.end, &span.style);
+    }
+
+    //pending user input looks like submitted user input
+    if let Some((start, end)) = input_range {
+        let input_style = model::Style { user_input: true, ..model::Style::default() };
+        insert_attributes(&attr_list, start, end, &input_style);
+    }
+
+    attr_list
+}
+
+fn insert_attributes(attr_list: &pango::AttrList, start: usize, end: usize, style: &model::Style) {
+    if start >= end {
+        return;
+    }
+    let mut attrs = Vec::new();
+
+    if style.foreground.is_some() || style.background.is_some() || style.reverse || style.dim || style.protected || style.user_input {
+        let (fg, bg) = palette::resolve_style(style);
+        let (r, g, b) = palette::to_pango_channels(fg);
+        attrs.push(pango::Attribute::new_foreground(r, g, b));
+        if style.background.is_some() || style.reverse || style.protected {
+            let (r, g, b) = palette::to_pango_channels(bg);
+            attrs.push(pango::Attribute::new_background(r, g, b));
+        }
+    }
+    if style.bold || style.user_input {
+        attrs.push(pango::Attribute::new_weight(pango::Weight::Bold));
+    }
+    if style.italic {
+        attrs.push(pango::Attribute::new_style(pango::Style::Italic));
+    }
+    if style.underline {
+        attrs.push(pango::Attribute::new_underline(pango::Underline::Single));
+    }
+    if style.strikethrough {
+        attrs.push(pango::Attribute::new_strikethrough(true));
     }
 
-    fn get_logical_extents(&self) -> pango::Rectangle {
-        self.layout.get_pixel_extents().1
+    for mut attr in attrs.into_iter().filter_map(|a| a) {
+        attr.set_start_index(start as u32);
+        attr.set_end_index(end as u32);
+        attr_list.insert(attr);
     }
 }
 
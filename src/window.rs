@@ -16,17 +16,21 @@
 *
 *******************************************************************************/
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::sync::mpsc;
 use gdk;
 use glib;
-use gtk::{self, DrawingArea, Window as GtkWindow, WindowType};
+use gtk::{self, Adjustment, DrawingArea, Orientation, Scrollbar, Window as GtkWindow, WindowType};
 use gtk::prelude::*;
 use vt6tokio::server::core::IncomingEvent;
 
+use client;
+use keyboard;
 use model;
 use util::AnchoredArc;
 use view;
@@ -34,29 +38,48 @@ use view;
 pub struct Window {
     window: GtkWindow,
     area: AnchoredArc<DrawingArea>,
+    ///Shared between the scrollbar and the view.
+    adjustment: Adjustment,
+    ///Shared by all WindowHandles.
+    throttle: Arc<RedrawThrottle>,
 }
 
 impl Window {
-    pub fn new() -> Window {
+    pub fn new(title: &str, class: Option<&str>) -> Window {
         gtk::init().unwrap();
 
         let w = Window {
             window: GtkWindow::new(WindowType::Toplevel),
             area: AnchoredArc::new(DrawingArea::new()),
+            adjustment: Adjustment::new(0., 0., 0., 1., 1., 0.),
+            throttle: Arc::new(RedrawThrottle {
+                pending: AtomicBool::new(false),
+                last_redraw: Mutex::new(Instant::now()),
+            }),
         };
-        w.window.set_title("6term");
-        w.window.add(w.area.as_ref());
+        w.window.set_title(title);
+        if let Some(class) = class {
+            w.window.set_wmclass(class, class);
+        }
+
+        let scrollbar = Scrollbar::new(Orientation::Vertical, &w.adjustment);
+        let hbox = gtk::Box::new(Orientation::Horizontal, 0);
+        hbox.pack_start(w.area.as_ref(), true, true, 0);
+        hbox.pack_start(&scrollbar, false, false, 0);
+        w.window.add(&hbox);
         w.window.show_all();
 
         w
     }
 
     pub fn handle(&self) -> WindowHandle {
-        WindowHandle(self.area.clone())
+        WindowHandle(self.area.clone(), self.throttle.clone())
     }
 
     ///Returns when the GUI thread is done, meaning that all other threads shall be shut down.
-    pub fn main(&mut self, tx: mpsc::Sender<IncomingEvent>, model: Arc<Mutex<model::Document>>) {
+    ///Each item sent into `restart_tx` asks for the client to be restarted
+    ///after it has exited. Control keys act on the client through `client`.
+    pub fn main(&mut self, tx: mpsc::Sender<IncomingEvent>, restart_tx: mpsc::Sender<()>, client: client::ClientHandle, model: Arc<Mutex<model::Document>>) {
 
         self.window.connect_delete_event(|_,_| {
             gtk::main_quit();
@@ -64,55 +87,200 @@ impl Window {
         });
 
         let view = Rc::new(RefCell::new(
-            view::Document::new(model.clone()),
+            view::Document::new(model.clone(), self.adjustment.clone()),
         ));
 
+        //redraw when the user moves the scrollbar
+        let area: DrawingArea = (*self.area).clone();
+        self.adjustment.connect_value_changed(move |_| area.queue_draw());
+
+        self.area.add_events(gdk::EventMask::SCROLL_MASK.bits() as i32);
+        let adjustment = self.adjustment.clone();
+        self.area.connect_scroll_event(move |_, event| {
+            use gdk::ScrollDirection::*;
+            match event.get_direction() {
+                Up => view::scroll_by(&adjustment, -view::SCROLL_STEP),
+                Down => view::scroll_by(&adjustment, view::SCROLL_STEP),
+                _ => return Inhibit(false),
+            }
+            Inhibit(true)
+        });
+
+        //the view measures the screen size while drawing; full-screen clients on
+        //a pseudo-terminal need to know when it changes
+        let draw_client = client.clone();
+        let draw_model = model.clone();
+        let client_screen_size = Cell::new((0, 0));
         self.area.connect_draw(move |widget, cairo_ctx| {
             view.borrow_mut().render(widget, cairo_ctx);
+            let size = draw_model.lock().unwrap().screen_size();
+            if client_screen_size.get() != size {
+                client_screen_size.set(size);
+                draw_client.resize(size);
+            }
             Inhibit(false)
         });
 
-        let tx = Rc::new(RefCell::new(tx));
+        let sender = Rc::new(InputSender {
+            tx: RefCell::new(tx),
+            retry_scheduled: Cell::new(false),
+        });
+        let restart_tx = RefCell::new(restart_tx);
 
         self.area.add_events(gdk::EventMask::KEY_PRESS_MASK.bits() as i32);
+        let adjustment = self.adjustment.clone();
         self.area.connect_key_press_event(move |widget, event| {
             let keyval = event.get_keyval();
-            let action = match gdk::keyval_to_unicode(keyval) {
-                //Enter or Return
-                Some('\n') | Some('\r') => model::CursorAction::Insert("\n".into()),
-                //Backspace
-                Some('\u{8}') => model::CursorAction::DeletePreviousChar,
-                //Delete
-                Some('\u{7F}') => model::CursorAction::DeleteNextChar,
-                //printable character
-                Some(ch) if ch as u32 >= 32 => model::CursorAction::Insert(ch.to_string()),
-                //ignore other control characters
-                Some(_) => return Inhibit(false),
-                //other keys
-                None => {
+
+            //Shift+PageUp/PageDown scroll the viewport
+            if event.get_state().contains(gdk::ModifierType::SHIFT_MASK) {
+                use gdk::enums::key;
+                let page = adjustment.get_page_increment();
+                match keyval as key::Key {
+                    key::Page_Up | key::KP_Page_Up => {
+                        view::scroll_by(&adjustment, -page);
+                        return Inhibit(true);
+                    },
+                    key::Page_Down | key::KP_Page_Down => {
+                        view::scroll_by(&adjustment, page);
+                        return Inhibit(true);
+                    },
+                    _ => {},
+                }
+            }
+
+            //with immediate input, keystrokes go to the client without local editing
+            {
+                let mut document = model.lock().unwrap();
+                if document.client_state == model::ClientState::Running && document.wants_immediate_input() {
+                    if let Some(input) = keyboard::encode(keyval, event.get_state()) {
+                        if document.input_queue.is_full() {
+                            widget.error_bell();
+                        } else {
+                            submit_input(&sender, &model, &mut document, widget, input);
+                        }
+                    }
+                    return Inhibit(true);
+                }
+
+                //control keys for signals and end of input (the keyval is
+                //lowercased, so that these work regardless of Shift and Caps Lock)
+                let state = event.get_state();
+                let ctrl = state.contains(gdk::ModifierType::CONTROL_MASK);
+                let shift = state.contains(gdk::ModifierType::SHIFT_MASK);
+                if document.client_state == model::ClientState::Running && ctrl {
                     use gdk::enums::key;
-                    match keyval as key::Key {
-                        key::Left  | key::KP_Left  => model::CursorAction::GotoPreviousChar,
-                        key::Right | key::KP_Right => model::CursorAction::GotoNextChar,
-                        _ => {
-                            info!("unhandled keyval: {}", keyval);
-                            return Inhibit(false);
+                    let signal = match gdk::keyval_to_lower(keyval) as key::Key {
+                        key::c => Some(model::ControlSignal::Interrupt),
+                        key::backslash => Some(model::ControlSignal::Quit),
+                        //(Ctrl+Shift+Z is redo, see editing_action())
+                        key::z if !shift => Some(model::ControlSignal::Suspend),
+                        key::d => {
+                            if document.has_pending_input() {
+                                //as in readline, Ctrl+D deletes the next character instead
+                                if document.execute_input_action(model::CursorAction::DeleteNextGrapheme) != model::CursorActionResult::Unchanged {
+                                    widget.queue_draw();
+                                }
+                            } else if !client.end_input() {
+                                widget.error_bell();
+                            }
+                            return Inhibit(true);
                         },
+                        _ => None,
+                    };
+                    if let Some(signal) = signal {
+                        //VT6-aware clients that have enabled the sig module
+                        //receive an event instead of a Unix signal
+                        if !document.deliver_signal(signal) && !client.signal(signal) {
+                            widget.error_bell();
+                        }
+                        return Inhibit(true);
                     }
+                }
+            }
+
+            let state = event.get_state();
+            let has_modifier = state.intersects(gdk::ModifierType::CONTROL_MASK | gdk::ModifierType::MOD1_MASK);
+            let action = match editing_action(keyval, state) {
+                Some(action) => action,
+                None => match gdk::keyval_to_unicode(keyval) {
+                    //Enter or Return
+                    Some('\n') | Some('\r') => model::CursorAction::Insert("\n".into()),
+                    //Backspace
+                    Some('\u{8}') => model::CursorAction::DeletePreviousGrapheme,
+                    //Delete
+                    Some('\u{7F}') => model::CursorAction::DeleteNextGrapheme,
+                    //printable character (unless it is part of an unknown shortcut)
+                    Some(ch) if ch as u32 >= 32 && !has_modifier => model::CursorAction::Insert(ch.to_string()),
+                    //ignore other control characters
+                    Some(_) => return Inhibit(false),
+                    //other keys
+                    None => {
+                        use gdk::enums::key;
+                        match keyval as key::Key {
+                            key::Left  | key::KP_Left  => model::CursorAction::GotoPreviousGrapheme,
+                            key::Right | key::KP_Right => model::CursorAction::GotoNextGrapheme,
+                            key::Home  | key::KP_Home  => model::CursorAction::GotoStart,
+                            key::End   | key::KP_End   => model::CursorAction::GotoEnd,
+                            _ => {
+                                info!("unhandled keyval: {}", keyval);
+                                return Inhibit(false);
+                            },
+                        }
+                    },
                 },
             };
             let mut document = model.lock().unwrap();
-            if let Some(ref mut p) = document.sections.last_mut() {
-                match p.execute_input_action(action) {
-                    model::CursorActionResult::Unchanged => {},
-                    model::CursorActionResult::Changed => widget.queue_draw(),
-                    model::CursorActionResult::LineCompleted(s) => {
-                        widget.queue_draw();
-                        //TODO check return value from try_send
-                        tx.borrow_mut().try_send(IncomingEvent::UserInput(s)).unwrap();
-                    },
+
+            //while there is no client, the only possible input is Enter to
+            //restart it (if allowed)
+            match document.client_state {
+                model::ClientState::Running => {},
+                model::ClientState::Exited => return Inhibit(false),
+                model::ClientState::ExitedRestartable => {
+                    if let model::CursorAction::Insert(ref text) = action {
+                        if text == "\n" {
+                            if let Err(e) = restart_tx.borrow_mut().try_send(()) {
+                                //if the channel is full, a restart is already pending
+                                if e.is_disconnected() {
+                                    error!("cannot restart client: eventloop is gone");
+                                }
+                            }
+                        }
+                    }
+                    return Inhibit(true);
+                },
+            }
+
+            //when the client does not keep up with our input, do not allow
+            //submitting more lines (but keep them editable)
+            if document.input_queue.is_full() {
+                if let model::CursorAction::Insert(ref text) = action {
+                    if text.contains('\n') {
+                        widget.error_bell();
+                        return Inhibit(true);
+                    }
                 }
             }
+
+            let result = document.execute_input_action(action);
+            match result {
+                model::CursorActionResult::Unchanged => {},
+                model::CursorActionResult::Changed => {
+                    view::scroll_to_bottom(&adjustment);
+                    widget.queue_draw();
+                },
+                model::CursorActionResult::LineCompleted(s) => {
+                    //output resulting from this input goes into a new section
+                    let section = document.make_section();
+                    document.sections.push(section);
+                    document.enforce_scrollback();
+                    view::scroll_to_bottom(&adjustment);
+                    widget.queue_draw();
+
+                    submit_input(&sender, &model, &mut document, widget, s);
+                },
+            }
             Inhibit(true)
         });
 
@@ -123,16 +291,143 @@ impl Window {
     }
 }
 
+///Maps the keyboard shortcuts for line editing (in addition to the plain
+///editing keys) to their actions, following readline.
+fn editing_action(keyval: gdk::enums::key::Key, state: gdk::ModifierType) -> Option<model::CursorAction> {
+    use gdk::enums::key;
+    use model::CursorAction::*;
+    let ctrl = state.contains(gdk::ModifierType::CONTROL_MASK);
+    let alt = state.contains(gdk::ModifierType::MOD1_MASK);
+    let shift = state.contains(gdk::ModifierType::SHIFT_MASK);
+
+    let action = match keyval {
+        key::Left  | key::KP_Left  if ctrl => GotoPreviousWord,
+        key::Right | key::KP_Right if ctrl => GotoNextWord,
+        key::BackSpace if alt => DeletePreviousWord,
+        key::a if ctrl => GotoStart,
+        key::e if ctrl => GotoEnd,
+        key::w if ctrl => DeletePreviousWord,
+        key::u if ctrl => KillToStart,
+        key::k if ctrl => KillToEnd,
+        key::y if ctrl => Yank,
+        key::t if ctrl => TransposeGraphemes,
+        key::underscore | key::slash if ctrl => Undo,
+        key::z | key::Z if ctrl && shift => Redo,
+        key::b if alt => GotoPreviousWord,
+        key::f if alt => GotoNextWord,
+        key::d if alt => DeleteNextWord,
+        key::y if alt => YankPop,
+        _ => return None,
+    };
+    Some(action)
+}
+
+///Delivers user input from the GUI thread to the server eventloop.
+struct InputSender {
+    tx: RefCell<mpsc::Sender<IncomingEvent>>,
+    ///Whether a retry of flush_input_queue() has been scheduled.
+    retry_scheduled: Cell<bool>,
+}
+
+///Queues user input (a completed line, or a keystroke with immediate input) for
+///delivery to the client, and tries to deliver it right away.
+fn submit_input(sender: &Rc<InputSender>, model: &Arc<Mutex<model::Document>>, document: &mut model::Document, widget: &DrawingArea, input: String) {
+    document.input_queue.push(input);
+    let all_delivered = flush_input_queue(&mut document.input_queue, &mut sender.tx.borrow_mut());
+    if all_delivered || sender.retry_scheduled.get() {
+        return;
+    }
+
+    //try again later (the retry gives up on its own when everything has been
+    //delivered)
+    sender.retry_scheduled.set(true);
+    let model = model.clone();
+    let sender = sender.clone();
+    let widget = widget.clone();
+    gtk::timeout_add(INPUT_RETRY_INTERVAL_MS, move || {
+        let mut document = model.lock().unwrap();
+        let all_delivered = flush_input_queue(&mut document.input_queue, &mut sender.tx.borrow_mut());
+        widget.queue_draw();
+        if all_delivered {
+            sender.retry_scheduled.set(false);
+            Continue(false)
+        } else {
+            Continue(true)
+        }
+    });
+}
+
+///How often delivery of queued user input is retried when the input channel is
+///full.
+const INPUT_RETRY_INTERVAL_MS: u32 = 20;
+
+///Sends queued user input into the server eventloop until the channel is full.
+///Returns whether all queued input was delivered.
+fn flush_input_queue(queue: &mut model::InputQueue, tx: &mut mpsc::Sender<IncomingEvent>) -> bool {
+    queue.flush(|line| {
+        match tx.try_send(IncomingEvent::UserInput(line.clone())) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.is_disconnected() => {
+                //nobody is listening anymore, so there is no point in retrying
+                error!("cannot deliver user input: server eventloop is gone");
+                Ok(())
+            },
+            Err(_) => Err(line),
+        }
+    })
+}
+
+///Redraws are limited to this rate, no matter how fast output arrives.
+const MIN_REDRAW_INTERVAL_MS: u32 = 16;
+
+///Coalesces redraw requests from WindowHandle::redraw().
+struct RedrawThrottle {
+    ///Whether a redraw has been scheduled on the GTK eventloop, but has not
+    ///happened yet. Further redraw requests are dropped while this is set.
+    pending: AtomicBool,
+    ///When the last scheduled redraw happened.
+    last_redraw: Mutex<Instant>,
+}
+
 #[derive(Clone)]
-pub struct WindowHandle(AnchoredArc<DrawingArea>);
+pub struct WindowHandle(AnchoredArc<DrawingArea>, Arc<RedrawThrottle>);
 
 impl WindowHandle {
-    ///Can be called by any thread to trigger a redraw of the GUI.
+    ///Can be called by any thread to trigger a redraw of the GUI. Multiple
+    ///requests are coalesced into a single redraw, and redraws are rate-limited.
     pub fn redraw(&self) {
+        let throttle = self.1.clone();
+        if throttle.pending.swap(true, Ordering::SeqCst) {
+            //redraw is already scheduled and will pick up the latest changes
+            return;
+        }
+
         let anchored_arc = self.0.clone();
-        glib::idle_add(move || {
+        let elapsed = throttle.last_redraw.lock().unwrap().elapsed();
+        let callback = move || {
+            //clear the flag before drawing, so that changes made during the
+            //draw schedule another redraw
+            throttle.pending.store(false, Ordering::SeqCst);
+            *throttle.last_redraw.lock().unwrap() = Instant::now();
             anchored_arc.queue_draw();
             Continue(false)
+        };
+
+        let min_interval = Duration::from_millis(MIN_REDRAW_INTERVAL_MS as u64);
+        if elapsed >= min_interval {
+            glib::idle_add(callback);
+        } else {
+            let remaining = min_interval - elapsed;
+            glib::timeout_add(remaining.subsec_nanos() / 1_000_000 + 1, callback);
+        }
+    }
+
+    ///Can be called by any thread to close the window, which ends the GUI
+    ///thread's main loop.
+    pub fn close(&self) {
+        glib::idle_add(|| {
+            gtk::main_quit();
+            Continue(false)
         });
     }
 }